@@ -0,0 +1,114 @@
+//! Downstream call benchmark
+//!
+//! Runs the real load_* and try_close paths against in-process
+//! UPL, Product and Pricing mock servers. Every mock call waits
+//! a network round-trip before answering.
+//!
+//! Run with: cargo test --release bench -- --ignored --nocapture
+//! Round-trip latency can be set by BENCH_LATENCY_MS (default 20)
+
+use crate::mock::{procurement_to_close, service};
+use std::time::{Duration, Instant};
+
+fn latency() -> Duration {
+  Duration::from_millis(
+    std::env::var("BENCH_LATENCY_MS")
+      .ok()
+      .and_then(|v| v.parse().ok())
+      .unwrap_or(20),
+  )
+}
+
+/// Run the given number of concurrent requests
+async fn concurrent_requests<F, Fut>(requests: u32, f: F) -> Duration
+where
+  F: Fn(u32) -> Fut,
+  Fut: std::future::Future<Output = ()>,
+{
+  let start = Instant::now();
+  futures::future::join_all((0..requests).map(f)).await;
+  start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration, baseline: Duration) {
+  println!(
+    "{:<40} {:>8.1} ms {:>8.1}x",
+    name,
+    elapsed.as_secs_f64() * 1000.0,
+    baseline.as_secs_f64() / elapsed.as_secs_f64()
+  );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn bench_load() {
+  let service = service(latency(), Vec::new()).await;
+  let skus = (1..=20).collect::<Vec<u32>>();
+  let upl_ids = (1..=100).map(|i| i.to_string()).collect::<Vec<String>>();
+  println!("Round-trip latency: {:?}\n", latency());
+
+  for requests in [1, 10, 50] {
+    println!(
+      "{} concurrent requests loading UPLs, SKUs and prices",
+      requests
+    );
+    let sequential = concurrent_requests(requests, |_| async {
+      service.load_upls(upl_ids.clone()).await.unwrap();
+      service.load_skus(skus.clone()).await.unwrap();
+      service.load_prices(skus.clone()).await.unwrap();
+    })
+    .await;
+    report("  sequential", sequential, sequential);
+
+    let concurrent = concurrent_requests(requests, |_| async {
+      tokio::try_join!(
+        service.load_upls(upl_ids.clone()),
+        service.load_skus(skus.clone()),
+        service.load_prices(skus.clone()),
+      )
+      .unwrap();
+    })
+    .await;
+    report("  concurrent", concurrent, sequential);
+    println!();
+  }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn bench_try_close() {
+  let max_requests = 50;
+  let service = service(
+    latency(),
+    (1..=max_requests)
+      .map(|id| procurement_to_close(id, 20, 5))
+      .collect(),
+  )
+  .await;
+  println!("Round-trip latency: {:?}\n", latency());
+
+  // Closing does not change the procurement,
+  // so the same ones can be closed again.
+  // Speedup is compared to closing them one by one
+  let single = concurrent_requests(1, |id| {
+    let service = &service;
+    async move {
+      service.try_close(id + 1).await.unwrap();
+    }
+  })
+  .await;
+  for requests in [1, 10, max_requests] {
+    let elapsed = concurrent_requests(requests, |id| {
+      let service = &service;
+      async move {
+        service.try_close(id + 1).await.unwrap();
+      }
+    })
+    .await;
+    report(
+      &format!("try_close, {} concurrent requests", requests),
+      elapsed,
+      single * requests,
+    );
+  }
+}
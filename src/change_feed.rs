@@ -0,0 +1,174 @@
+use crate::procurement::{Procurement, Status};
+use chrono::Utc;
+use gzlib::proto::procurement::{ChangeEvent, ChangeKind, WatchRequest};
+use std::collections::VecDeque;
+use tokio::sync::{broadcast, Mutex};
+
+struct Buffer {
+  last_seq: u64,
+  events: VecDeque<ChangeEvent>,
+  capacity: usize,
+}
+
+/// Procurement change events
+/// The last events are buffered in memory, so a watcher can
+/// resume from a sequence number. Sequence restarts with the service,
+/// the epoch tells which run a sequence number belongs to.
+pub struct ChangeFeed {
+  epoch: String,
+  buffer: Mutex<Buffer>,
+  sender: broadcast::Sender<ChangeEvent>,
+}
+
+impl ChangeFeed {
+  pub fn new(capacity: usize) -> Self {
+    let capacity = capacity.max(1);
+    let (sender, _) = broadcast::channel(capacity);
+    Self {
+      epoch: format!(
+        "{}-{:08x}",
+        Utc::now().timestamp_millis(),
+        rand::random::<u32>()
+      ),
+      buffer: Mutex::new(Buffer {
+        last_seq: 0,
+        events: VecDeque::new(),
+        capacity,
+      }),
+      sender,
+    }
+  }
+
+  /// Publish change event of a procurement
+  /// Payload is the procurement info after the change
+  pub async fn publish(&self, kind: ChangeKind, procurement: &Procurement) {
+    let mut buffer = self.buffer.lock().await;
+    buffer.last_seq += 1;
+    let event = ChangeEvent {
+      seq: buffer.last_seq,
+      epoch: self.epoch.clone(),
+      procurement_id: procurement.id,
+      version: procurement.version,
+      kind: kind as i32,
+      created_at: Utc::now().to_rfc3339(),
+      payload: Some(procurement.clone().into()),
+    };
+    if buffer.events.len() >= buffer.capacity {
+      buffer.events.pop_front();
+    }
+    buffer.events.push_back(event.clone());
+    // Having no watcher is not an error
+    let _ = self.sender.send(event);
+  }
+
+  /// Subscribe to the change events
+  /// Returns the buffered events after from_seq and the receiver
+  /// of the new events. from_seq 0 means new events only.
+  /// Resuming needs the epoch of the events already seen.
+  pub async fn subscribe(
+    &self,
+    from_seq: u64,
+    epoch: &str,
+  ) -> Result<(Vec<ChangeEvent>, broadcast::Receiver<ChangeEvent>), String> {
+    if from_seq > 0 && epoch != self.epoch {
+      return Err(
+        "A sorszám egy korábbi futásból származik! Kérem töltse újra a beszerzéseket.".to_string(),
+      );
+    }
+    // Buffer lock is held while subscribing,
+    // so no event is lost or sent twice
+    let buffer = self.buffer.lock().await;
+    if from_seq > buffer.last_seq {
+      return Err("Ismeretlen sorszám! Kérem töltse újra a beszerzéseket.".to_string());
+    }
+    let oldest = buffer
+      .events
+      .front()
+      .map(|e| e.seq)
+      .unwrap_or(buffer.last_seq + 1);
+    if from_seq > 0 && from_seq + 1 < oldest {
+      return Err("A sorszám már nem érhető el! Kérem töltse újra a beszerzéseket.".to_string());
+    }
+    let backlog = match from_seq {
+      0 => Vec::new(),
+      _ => buffer
+        .events
+        .iter()
+        .filter(|e| e.seq > from_seq)
+        .cloned()
+        .collect(),
+    };
+    Ok((backlog, self.sender.subscribe()))
+  }
+}
+
+/// Record a change on the procurement
+/// Bumps its version and writes the integration event,
+/// so both are persisted with the change itself.
+/// Returns the changed procurement to publish
+pub fn record(procurement: &mut Procurement, kind: ChangeKind) -> Procurement {
+  procurement.bump_version();
+  let event_type = match (kind, &procurement.status) {
+    (ChangeKind::Created, _) => "procurement.created",
+    (ChangeKind::Updated, _) => "procurement.updated",
+    (ChangeKind::ItemChanged, _) => "procurement.items_changed",
+    (ChangeKind::UplChanged, _) => "procurement.upls_changed",
+    (ChangeKind::StatusChanged, Status::New) => "procurement.status_changed",
+    (ChangeKind::StatusChanged, Status::Ordered) => "procurement.ordered",
+    (ChangeKind::StatusChanged, Status::Arrived) => "procurement.arrived",
+    (ChangeKind::StatusChanged, Status::Processing) => "procurement.processing",
+    (ChangeKind::StatusChanged, Status::Closed) => "procurement.closed",
+    (ChangeKind::Removed, _) => "procurement.removed",
+  };
+  procurement.record_event(event_type);
+  procurement.clone()
+}
+
+/// Check if event matches the watch filters
+/// Empty filter means any
+pub fn matches(filter: &WatchRequest, event: &ChangeEvent) -> bool {
+  let (status, source_id) = match &event.payload {
+    Some(p) => (p.status, p.source_id),
+    None => return false,
+  };
+  (filter.procurement_ids.is_empty() || filter.procurement_ids.contains(&event.procurement_id))
+    && (filter.statuses.is_empty() || filter.statuses.contains(&status))
+    && (filter.source_ids.is_empty() || filter.source_ids.contains(&source_id))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_subscribe() {
+    let feed = ChangeFeed::new(2);
+    let procurement = Procurement::new(1, 1, 1, 1);
+    for _ in 0..4 {
+      feed.publish(ChangeKind::Updated, &procurement).await;
+    }
+    let (backlog, _) = feed.subscribe(0, "").await.unwrap();
+    assert!(backlog.is_empty());
+    let (backlog, _) = feed.subscribe(2, &feed.epoch).await.unwrap();
+    assert_eq!(
+      backlog.iter().map(|e| e.seq).collect::<Vec<u64>>(),
+      vec![3, 4]
+    );
+    assert_eq!(backlog[0].epoch, feed.epoch);
+    // Sequence 2 was dropped from the buffer
+    assert!(feed.subscribe(1, &feed.epoch).await.is_err());
+    assert!(feed.subscribe(5, &feed.epoch).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_subscribe_epoch_mismatch() {
+    let old_feed = ChangeFeed::new(10);
+    let feed = ChangeFeed::new(10);
+    let procurement = Procurement::new(1, 1, 1, 1);
+    feed.publish(ChangeKind::Updated, &procurement).await;
+    feed.publish(ChangeKind::Updated, &procurement).await;
+    assert!(feed.subscribe(1, &old_feed.epoch).await.is_err());
+    assert!(feed.subscribe(1, "").await.is_err());
+    assert_eq!(feed.subscribe(1, &feed.epoch).await.unwrap().0.len(), 1);
+  }
+}
@@ -0,0 +1,420 @@
+use crate::prelude::{env_or, service_address, ServiceError, ServiceResult};
+use chrono::prelude::*;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Status};
+
+/// Create a lazy channel to a downstream service
+/// Connection is made on the first call and re-made after failures,
+/// so the service starts while its dependencies are down
+pub fn connect_lazy(env_key: &'static str) -> Channel {
+  let addr = service_address(env_key);
+  Endpoint::from_shared(addr.clone())
+    .unwrap_or_else(|_| panic!("Invalid service address for {}: {}", env_key, addr))
+    .connect_lazy()
+    .unwrap_or_else(|e| panic!("Could not create channel for {}: {}", env_key, e))
+}
+
+/// Errors meaning the service is not reachable or overloaded,
+/// these are retried
+fn is_transient(status: &Status) -> bool {
+  matches!(
+    status.code(),
+    Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted
+  )
+}
+
+/// Errors counted by the breaker
+/// Transport errors arrive as Unknown
+fn is_failure(status: &Status) -> bool {
+  is_transient(status) || status.code() == Code::Unknown
+}
+
+/// Errors after which the request may have been processed
+fn is_unknown_outcome(status: &Status) -> bool {
+  matches!(status.code(), Code::DeadlineExceeded | Code::Unknown)
+}
+
+/// Error of a call that is not safe to repeat
+pub enum OnceError {
+  /// Not sent, the breaker is open
+  NotSent(ServiceError),
+  /// Sent, but it is unknown whether it was processed
+  UnknownOutcome(ServiceError),
+  /// Sent and failed
+  Failed(ServiceError),
+}
+
+impl From<OnceError> for ServiceError {
+  fn from(error: OnceError) -> Self {
+    match error {
+      OnceError::NotSent(e) | OnceError::UnknownOutcome(e) | OnceError::Failed(e) => e,
+    }
+  }
+}
+
+#[derive(Default)]
+struct BreakerState {
+  consecutive_failures: u32,
+  open_until: Option<Instant>,
+  last_error: String,
+  last_failure_at: Option<DateTime<Utc>>,
+}
+
+/// Circuit breaker state of a downstream service
+pub struct DownstreamStatus {
+  pub name: &'static str,
+  pub available: bool,
+  pub state: &'static str,
+  pub consecutive_failures: u32,
+  pub last_error: String,
+  pub last_failure_at: Option<DateTime<Utc>>,
+  // Features not working while the service is unavailable
+  pub features: &'static [&'static str],
+}
+
+/// Downstream service client with per-call deadline,
+/// retry with backoff and circuit breaker.
+/// Clones share the breaker.
+#[derive(Clone)]
+pub struct Downstream<C> {
+  name: &'static str,
+  client: C,
+  features: &'static [&'static str],
+  breaker: Arc<Mutex<BreakerState>>,
+  deadline: Duration,
+  retries: u32,
+  failure_threshold: u32,
+  cooldown: Duration,
+}
+
+impl<C: Clone> Downstream<C> {
+  pub fn new(name: &'static str, client: C, features: &'static [&'static str]) -> Self {
+    Self {
+      name,
+      client,
+      features,
+      breaker: Arc::new(Mutex::new(BreakerState::default())),
+      deadline: Duration::from_millis(env_or("PROCUREMENT_DOWNSTREAM_TIMEOUT_MS", 5000)),
+      retries: env_or("PROCUREMENT_DOWNSTREAM_RETRIES", 2),
+      failure_threshold: env_or("PROCUREMENT_BREAKER_FAILURES", 5).max(1),
+      cooldown: Duration::from_secs(env_or("PROCUREMENT_BREAKER_COOLDOWN_SECS", 30)),
+    }
+  }
+
+  fn unavailable(&self) -> ServiceError {
+    ServiceError::unavailable(&format!(
+      "A(z) {} szolgáltatás jelenleg nem elérhető! Kérem próbálja újra később.",
+      self.name
+    ))
+  }
+
+  /// Open breaker rejects the calls until the cooldown is over,
+  /// then calls are let through to probe the service
+  fn allow(&self) -> bool {
+    match self.breaker.lock().unwrap().open_until {
+      Some(open_until) => Instant::now() >= open_until,
+      None => true,
+    }
+  }
+
+  fn succeeded(&self) {
+    let mut state = self.breaker.lock().unwrap();
+    if state.open_until.is_some() {
+      eprintln!("{} service is available again", self.name);
+    }
+    state.consecutive_failures = 0;
+    state.open_until = None;
+  }
+
+  fn failed(&self, status: &Status) {
+    let mut state = self.breaker.lock().unwrap();
+    state.consecutive_failures += 1;
+    state.last_error = status.message().to_string();
+    state.last_failure_at = Some(Utc::now());
+    if state.consecutive_failures >= self.failure_threshold {
+      if state.open_until.is_none() {
+        eprintln!(
+          "{} service is unavailable, circuit open: {}",
+          self.name,
+          status.message()
+        );
+      }
+      state.open_until = Some(Instant::now() + self.cooldown);
+    }
+  }
+
+  /// Make a single attempt within the deadline
+  async fn attempt<T, Fut>(&self, deadline: Duration, fut: Fut) -> Result<T, Status>
+  where
+    Fut: Future<Output = Result<T, Status>>,
+  {
+    let res = match tokio::time::timeout(deadline, fut).await {
+      Ok(res) => res,
+      Err(_) => Err(Status::deadline_exceeded(format!(
+        "{} service did not answer in {:?}",
+        self.name, deadline
+      ))),
+    };
+    match &res {
+      Err(status) if is_failure(status) => self.failed(status),
+      _ => self.succeeded(),
+    }
+    res
+  }
+
+  /// Transient errors mean the service is unavailable,
+  /// rejected requests are returned to the user,
+  /// any other error is internal
+  fn map_error(&self, status: Status) -> ServiceError {
+    match status.code() {
+      _ if is_transient(&status) => self.unavailable(),
+      Code::InvalidArgument | Code::NotFound | Code::FailedPrecondition => {
+        ServiceError::bad_request(status.message())
+      }
+      _ => ServiceError::internal_error(&format!(
+        "A(z) {} szolgáltatás hibája! {}",
+        self.name,
+        status.message()
+      )),
+    }
+  }
+
+  /// Call the service, transient errors are retried with backoff
+  /// Only for idempotent calls
+  pub async fn call<T, F, Fut>(&self, f: F) -> ServiceResult<T>
+  where
+    F: Fn(C) -> Fut,
+    Fut: Future<Output = Result<T, Status>>,
+  {
+    let mut attempt = 0;
+    loop {
+      if !self.allow() {
+        return Err(self.unavailable());
+      }
+      match self.attempt(self.deadline, f(self.client.clone())).await {
+        Ok(res) => return Ok(res),
+        Err(status) if is_transient(&status) && attempt < self.retries => {
+          // 100ms, 200ms, 400ms ..
+          tokio::time::sleep(Duration::from_millis(100 * 2u64.pow(attempt.min(6)))).await;
+          attempt += 1;
+        }
+        Err(status) => return Err(self.map_error(status)),
+      }
+    }
+  }
+
+  /// Call the service once, for calls that are not safe to repeat
+  pub async fn call_once<T, F, Fut>(&self, f: F) -> ServiceResult<T>
+  where
+    F: FnOnce(C) -> Fut,
+    Fut: Future<Output = Result<T, Status>>,
+  {
+    Ok(self.call_once_within(self.deadline, f).await?)
+  }
+
+  /// Call the service once with its own deadline,
+  /// for long running calls.
+  /// Error tells whether the request could have been processed
+  pub async fn call_once_within<T, F, Fut>(&self, deadline: Duration, f: F) -> Result<T, OnceError>
+  where
+    F: FnOnce(C) -> Fut,
+    Fut: Future<Output = Result<T, Status>>,
+  {
+    if !self.allow() {
+      return Err(OnceError::NotSent(self.unavailable()));
+    }
+    self
+      .attempt(deadline, f(self.client.clone()))
+      .await
+      .map_err(|status| match is_unknown_outcome(&status) {
+        true => OnceError::UnknownOutcome(self.map_error(status)),
+        false => OnceError::Failed(self.map_error(status)),
+      })
+  }
+
+  /// Get breaker state
+  pub fn status(&self) -> DownstreamStatus {
+    let state = self.breaker.lock().unwrap();
+    let (available, breaker_state) = match state.open_until {
+      Some(open_until) if Instant::now() < open_until => (false, "open"),
+      Some(_) => (false, "half_open"),
+      None => (true, "closed"),
+    };
+    DownstreamStatus {
+      name: self.name,
+      available,
+      state: breaker_state,
+      consecutive_failures: state.consecutive_failures,
+      last_error: state.last_error.clone(),
+      last_failure_at: state.last_failure_at,
+      features: self.features,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicU32, Ordering};
+
+  fn downstream(failure_threshold: u32, cooldown_ms: u64) -> Downstream<()> {
+    Downstream {
+      name: "test",
+      client: (),
+      features: &[],
+      breaker: Arc::new(Mutex::new(BreakerState::default())),
+      deadline: Duration::from_millis(50),
+      retries: 2,
+      failure_threshold,
+      cooldown: Duration::from_millis(cooldown_ms),
+    }
+  }
+
+  async fn fail_once(d: &Downstream<()>, status: Status) -> ServiceResult<()> {
+    d.call_once(|_| async move { Err(status) }).await
+  }
+
+  #[tokio::test]
+  async fn test_breaker_opens_at_threshold() {
+    let d = downstream(2, 10_000);
+    assert!(fail_once(&d, Status::unavailable("le")).await.is_err());
+    assert_eq!(d.status().state, "closed");
+    assert_eq!(d.status().consecutive_failures, 1);
+    assert!(fail_once(&d, Status::unavailable("le")).await.is_err());
+    let status = d.status();
+    assert_eq!(status.state, "open");
+    assert!(!status.available);
+    assert_eq!(status.last_error, "le");
+    // Open breaker rejects without calling
+    let calls = AtomicU32::new(0);
+    let res = d
+      .call_once(|_| async {
+        calls.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+      })
+      .await;
+    assert!(matches!(res, Err(ServiceError::Unavailable(_))));
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+  }
+
+  #[tokio::test]
+  async fn test_breaker_half_open_and_close() {
+    let d = downstream(1, 20);
+    assert!(fail_once(&d, Status::unavailable("le")).await.is_err());
+    assert_eq!(d.status().state, "open");
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(d.status().state, "half_open");
+    // Failed probe opens it again
+    assert!(fail_once(&d, Status::unavailable("le")).await.is_err());
+    assert_eq!(d.status().state, "open");
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    // Successful probe closes it
+    assert!(d.call_once(|_| async { Ok(()) }).await.is_ok());
+    let status = d.status();
+    assert_eq!(status.state, "closed");
+    assert!(status.available);
+    assert_eq!(status.consecutive_failures, 0);
+  }
+
+  #[tokio::test]
+  async fn test_non_transient_errors() {
+    let d = downstream(1, 10_000);
+    let res = fail_once(&d, Status::invalid_argument("hibás")).await;
+    assert!(matches!(res, Err(ServiceError::BadRequest(_))));
+    let res = fail_once(&d, Status::failed_precondition("hibás")).await;
+    assert!(matches!(res, Err(ServiceError::BadRequest(_))));
+    let res = fail_once(&d, Status::internal("hiba")).await;
+    assert!(matches!(res, Err(ServiceError::InternalError(_))));
+    assert_eq!(d.status().state, "closed");
+    assert_eq!(d.status().consecutive_failures, 0);
+    // Transport errors are not retried, but count against the breaker
+    let res = fail_once(&d, Status::unknown("transport error")).await;
+    assert!(matches!(res, Err(ServiceError::InternalError(_))));
+    assert_eq!(d.status().state, "open");
+  }
+
+  #[tokio::test]
+  async fn test_once_error_outcome() {
+    let d = downstream(2, 10_000);
+    let call = |status: Status| {
+      d.call_once_within(Duration::from_millis(50), |_| async move {
+        Err::<(), Status>(status)
+      })
+    };
+    assert!(matches!(
+      call(Status::unavailable("le")).await,
+      Err(OnceError::Failed(ServiceError::Unavailable(_)))
+    ));
+    assert!(matches!(
+      call(Status::deadline_exceeded("lassú")).await,
+      Err(OnceError::UnknownOutcome(ServiceError::Unavailable(_)))
+    ));
+    // Breaker is open, nothing is sent
+    assert!(matches!(
+      call(Status::unknown("transport error")).await,
+      Err(OnceError::NotSent(ServiceError::Unavailable(_)))
+    ));
+  }
+
+  #[tokio::test]
+  async fn test_deadline() {
+    let d = downstream(5, 10_000);
+    let res = d
+      .call_once(|_| async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        Ok(())
+      })
+      .await;
+    assert!(matches!(res, Err(ServiceError::Unavailable(_))));
+    assert_eq!(d.status().consecutive_failures, 1);
+    // Own deadline for long calls
+    let res = d
+      .call_once_within(Duration::from_millis(500), |_| async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        Ok(())
+      })
+      .await;
+    assert!(res.is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_call_retries_transient_errors() {
+    let d = downstream(10, 10_000);
+    let calls = AtomicU32::new(0);
+    let res = d
+      .call(|_| async {
+        match calls.fetch_add(1, Ordering::SeqCst) {
+          0 | 1 => Err(Status::unavailable("le")),
+          _ => Ok(()),
+        }
+      })
+      .await;
+    assert!(res.is_ok());
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    // Gives up after the retries
+    calls.store(0, Ordering::SeqCst);
+    let res: ServiceResult<()> = d
+      .call(|_| async {
+        calls.fetch_add(1, Ordering::SeqCst);
+        Err(Status::unavailable("le"))
+      })
+      .await;
+    assert!(res.is_err());
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    // Transport errors are not retried
+    calls.store(0, Ordering::SeqCst);
+    let res: ServiceResult<()> = d
+      .call(|_| async {
+        calls.fetch_add(1, Ordering::SeqCst);
+        Err(Status::unknown("transport error"))
+      })
+      .await;
+    assert!(res.is_err());
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+  }
+}
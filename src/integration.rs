@@ -0,0 +1,375 @@
+use crate::prelude::{env_or, http_client};
+use crate::procurement::IntegrationEvent;
+use crate::store::ProcurementStore;
+use chrono::prelude::*;
+use gzlib::proto::integration::{integration_client::IntegrationClient, IntegrationEventMessage};
+use packman::VecPack;
+use std::{
+  collections::{HashMap, HashSet},
+  sync::Arc,
+};
+use tokio::sync::Mutex;
+use tonic::transport::{Channel, Endpoint};
+
+/// Env key of the integration endpoint
+/// http(s)://... posts events as JSON, grpc://host:port uses
+/// the integration gRPC service
+const ENDPOINT_ENV_KEY: &str = "PROCUREMENT_INTEGRATION_ENDPOINT";
+
+/// Integration event delivery target
+/// Receiver must deduplicate by event ID,
+/// as an event can be delivered more than once
+#[tonic::async_trait]
+pub trait EventSink: Send + Sync {
+  async fn deliver(&self, event: &IntegrationEvent) -> Result<(), String>;
+}
+
+/// Posts events as JSON to an HTTP endpoint
+/// Event ID is sent in the Idempotency-Key header as well
+pub struct HttpSink {
+  client: reqwest::Client,
+  url: String,
+}
+
+#[tonic::async_trait]
+impl EventSink for HttpSink {
+  async fn deliver(&self, event: &IntegrationEvent) -> Result<(), String> {
+    let payload: serde_json::Value =
+      serde_json::from_str(&event.payload).map_err(|e| e.to_string())?;
+    self
+      .client
+      .post(&self.url)
+      .header("Idempotency-Key", &event.id)
+      .json(&serde_json::json!({
+        "id": event.id,
+        "source": "procurement",
+        "event_type": event.event_type,
+        "payload": payload,
+        "created_at": event.created_at.to_rfc3339(),
+      }))
+      .send()
+      .await
+      .and_then(|res| res.error_for_status())
+      .map_err(|e| e.to_string())?;
+    Ok(())
+  }
+}
+
+/// Publishes events through the integration gRPC service
+pub struct GrpcSink {
+  client: IntegrationClient<Channel>,
+}
+
+#[tonic::async_trait]
+impl EventSink for GrpcSink {
+  async fn deliver(&self, event: &IntegrationEvent) -> Result<(), String> {
+    self
+      .client
+      .clone()
+      .publish(IntegrationEventMessage {
+        id: event.id.clone(),
+        source: "procurement".to_string(),
+        event_type: event.event_type.clone(),
+        payload: event.payload.clone(),
+        created_at: event.created_at.to_rfc3339(),
+      })
+      .await
+      .map_err(|e| e.to_string())?;
+    Ok(())
+  }
+}
+
+/// Create event sink from the configured endpoint
+/// None if integration is not configured
+pub fn sink_from_env() -> Option<Box<dyn EventSink>> {
+  let endpoint = std::env::var(ENDPOINT_ENV_KEY).ok()?;
+  let endpoint = endpoint.trim();
+  if endpoint.is_empty() {
+    return None;
+  }
+  if let Some(addr) = endpoint.strip_prefix("grpc://") {
+    // Connect lazily, so the service starts when
+    // the endpoint is not available yet
+    match Endpoint::from_shared(format!("http://{}", addr))
+      .map_err(|e| e.to_string())
+      .and_then(|e| e.connect_lazy().map_err(|e| e.to_string()))
+    {
+      Ok(channel) => {
+        return Some(Box::new(GrpcSink {
+          client: IntegrationClient::new(channel),
+        }))
+      }
+      Err(e) => {
+        eprintln!("Invalid integration endpoint {}: {}", endpoint, e);
+        return None;
+      }
+    }
+  }
+  Some(Box::new(HttpSink {
+    client: http_client(),
+    url: endpoint.to_string(),
+  }))
+}
+
+/// Delivery progress of a not yet acknowledged event
+#[derive(Default)]
+struct Progress {
+  attempts: u32,
+  next_attempt_at: Option<DateTime<Utc>>,
+  // Index of the sinks that already took the event
+  acked_sinks: HashSet<usize>,
+}
+
+/// Delivery progress by event ID
+type Backoff = HashMap<String, Progress>;
+
+fn is_due(backoff: &Backoff, event_id: &str, now: DateTime<Utc>) -> bool {
+  match backoff.get(event_id).and_then(|p| p.next_attempt_at) {
+    Some(next_attempt_at) => next_attempt_at <= now,
+    None => true,
+  }
+}
+
+fn attempt_failed(backoff: &mut Backoff, event: &IntegrationEvent, error: String) {
+  let progress = backoff.entry(event.id.clone()).or_default();
+  progress.attempts += 1;
+  // 5s, 10s, 20s .. max 10 minutes
+  let delay = (5 * 2i64.pow(progress.attempts.min(8) - 1)).min(600);
+  eprintln!(
+    "Error while delivering integration event {} (attempt {}): {}",
+    event.id, progress.attempts, error
+  );
+  progress.next_attempt_at = Some(Utc::now() + chrono::Duration::seconds(delay));
+}
+
+/// Deliver an event to every sink that has not taken it yet
+async fn deliver(
+  sinks: &[Box<dyn EventSink>],
+  event: &IntegrationEvent,
+  backoff: &mut Backoff,
+) -> Result<(), String> {
+  for (index, sink) in sinks.iter().enumerate() {
+    let progress = backoff.entry(event.id.clone()).or_default();
+    if progress.acked_sinks.contains(&index) {
+      continue;
+    }
+    sink.deliver(event).await?;
+    progress.acked_sinks.insert(index);
+  }
+  Ok(())
+}
+
+/// Deliver pending events once
+/// Events of a procurement are delivered in order;
+/// a failed event holds back the later ones
+async fn relay_once(
+  sinks: &[Box<dyn EventSink>],
+  procurements: &ProcurementStore,
+  outbox: &Mutex<VecPack<IntegrationEvent>>,
+  backoff: &mut Backoff,
+) {
+  let now = Utc::now();
+
+  // Do not hold the lock while delivering
+  let pending = procurements
+    .read()
+    .await
+    .iter()
+    .map(|p| p.unpack())
+    .filter(|p| !p.outbox.is_empty())
+    .map(|p| (p.id, p.outbox.clone()))
+    .collect::<Vec<(u32, Vec<IntegrationEvent>)>>();
+
+  for (procurement_id, events) in pending {
+    for event in events {
+      if !is_due(backoff, &event.id, now) {
+        break;
+      }
+      if let Err(e) = deliver(sinks, &event, backoff).await {
+        attempt_failed(backoff, &event, e);
+        break;
+      }
+      backoff.remove(&event.id);
+      // If the procurement is claimed by a long running operation,
+      // the event is delivered again later
+      if let Ok(mut locked) = procurements.lock(procurement_id).await {
+        locked.procurement.event_delivered(&event.id);
+        if let Err(e) = locked.save().await {
+          eprintln!("Error while acknowledging integration event: {}", e);
+        }
+      }
+    }
+  }
+
+  // Events of the removed procurements
+  let mut removed = outbox
+    .lock()
+    .await
+    .iter()
+    .map(|e| e.unpack().clone())
+    .collect::<Vec<IntegrationEvent>>();
+  removed.sort_by_key(|e| e.created_at);
+
+  // Procurements with a failed or not yet due event
+  let mut held_back = HashSet::new();
+  for event in removed {
+    if held_back.contains(&event.procurement_id) {
+      continue;
+    }
+    if !is_due(backoff, &event.id, now) {
+      held_back.insert(event.procurement_id);
+      continue;
+    }
+    if let Err(e) = deliver(sinks, &event, backoff).await {
+      attempt_failed(backoff, &event, e);
+      held_back.insert(event.procurement_id);
+      continue;
+    }
+    backoff.remove(&event.id);
+    if let Err(e) = outbox.lock().await.remove_pack(&event.id) {
+      eprintln!("Error while removing delivered integration event: {}", e);
+    }
+  }
+}
+
+/// Relay integration events to the sinks forever
+/// Delivery is at-least-once; if a sink fails, the event is
+/// delivered again to the sinks that have not taken it yet.
+/// Progress is kept in memory, so after a restart
+/// the event can reach a sink twice
+pub async fn relay(
+  sinks: Vec<Box<dyn EventSink>>,
+  procurements: Arc<ProcurementStore>,
+  outbox: Arc<Mutex<VecPack<IntegrationEvent>>>,
+) {
+  let interval = env_or("PROCUREMENT_INTEGRATION_RELAY_INTERVAL_SECS", 5);
+  let mut backoff = Backoff::new();
+  loop {
+    relay_once(&sinks, &procurements, &outbox, &mut backoff).await;
+    tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Mutex as SyncMutex;
+
+  // Fails the events with the given IDs, records the delivered ones
+  struct TestSink {
+    failing: Vec<String>,
+    delivered: Arc<SyncMutex<Vec<String>>>,
+  }
+
+  #[tonic::async_trait]
+  impl EventSink for TestSink {
+    async fn deliver(&self, event: &IntegrationEvent) -> Result<(), String> {
+      if self.failing.contains(&event.id) {
+        return Err("hiba".to_string());
+      }
+      self.delivered.lock().unwrap().push(event.id.clone());
+      Ok(())
+    }
+  }
+
+  // Fails the first given number of calls, counts all calls
+  struct FlakySink {
+    failures: SyncMutex<u32>,
+    calls: Arc<SyncMutex<u32>>,
+  }
+
+  #[tonic::async_trait]
+  impl EventSink for FlakySink {
+    async fn deliver(&self, _event: &IntegrationEvent) -> Result<(), String> {
+      *self.calls.lock().unwrap() += 1;
+      let mut failures = self.failures.lock().unwrap();
+      if *failures > 0 {
+        *failures -= 1;
+        return Err("hiba".to_string());
+      }
+      Ok(())
+    }
+  }
+
+  fn flaky_sink(failures: u32) -> (Box<dyn EventSink>, Arc<SyncMutex<u32>>) {
+    let calls = Arc::new(SyncMutex::new(0));
+    let sink = FlakySink {
+      failures: SyncMutex::new(failures),
+      calls: calls.clone(),
+    };
+    (Box::new(sink), calls)
+  }
+
+  #[tokio::test]
+  async fn test_deliver_skips_acked_sinks() {
+    let (first, first_calls) = flaky_sink(0);
+    let (second, second_calls) = flaky_sink(1);
+    let sinks = vec![first, second];
+    let event = event("1-a", 1, "2021-03-01T10:00:00Z");
+    let mut backoff = Backoff::new();
+
+    assert!(deliver(&sinks, &event, &mut backoff).await.is_err());
+    attempt_failed(&mut backoff, &event, "hiba".to_string());
+    assert!(!is_due(&backoff, &event.id, Utc::now()));
+    assert!(deliver(&sinks, &event, &mut backoff).await.is_ok());
+    assert_eq!(*first_calls.lock().unwrap(), 1);
+    assert_eq!(*second_calls.lock().unwrap(), 2);
+  }
+
+  fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!(
+      "procurement_test_{}_{}",
+      name,
+      rand::random::<u32>()
+    ))
+  }
+
+  fn event(id: &str, procurement_id: u32, created_at: &str) -> IntegrationEvent {
+    IntegrationEvent {
+      id: id.to_string(),
+      event_type: "procurement.removed".to_string(),
+      procurement_id,
+      version: 1,
+      payload: "{}".to_string(),
+      created_at: created_at.parse().unwrap(),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_removed_events_held_back_per_procurement() {
+    let procurements =
+      ProcurementStore::new(VecPack::load_or_init(temp_path("procurement")).unwrap());
+    let mut removed = VecPack::load_or_init(temp_path("integration_outbox")).unwrap();
+    removed
+      .insert(event("1-a", 1, "2021-03-01T10:00:00Z"))
+      .unwrap();
+    removed
+      .insert(event("1-b", 1, "2021-03-01T10:01:00Z"))
+      .unwrap();
+    removed
+      .insert(event("2-a", 2, "2021-03-01T10:02:00Z"))
+      .unwrap();
+    let outbox = Mutex::new(removed);
+    let delivered = Arc::new(SyncMutex::new(Vec::new()));
+    let sinks: Vec<Box<dyn EventSink>> = vec![Box::new(TestSink {
+      failing: vec!["1-a".to_string()],
+      delivered: delivered.clone(),
+    })];
+    let mut backoff = Backoff::new();
+
+    relay_once(&sinks, &procurements, &outbox, &mut backoff).await;
+    // Later event of procurement 1 waits, procurement 2 is not blocked
+    assert_eq!(*delivered.lock().unwrap(), vec!["2-a".to_string()]);
+    let left = outbox
+      .lock()
+      .await
+      .iter()
+      .map(|e| e.unpack().id.clone())
+      .collect::<Vec<String>>();
+    assert_eq!(left, vec!["1-a".to_string(), "1-b".to_string()]);
+
+    // Not due yet, still held back
+    relay_once(&sinks, &procurements, &outbox, &mut backoff).await;
+    assert_eq!(delivered.lock().unwrap().len(), 1);
+  }
+}
@@ -0,0 +1,228 @@
+use crate::downstream::Downstream;
+use crate::notification::AlertEvent;
+use crate::notifier::NotificationCenter;
+use crate::price_history::PriceHistory;
+use crate::procurement::{Procurement, Status};
+use crate::store::ProcurementStore;
+use chrono::prelude::*;
+use gzlib::proto::upl::{upl_client::UplClient, BulkRequest};
+use packman::VecPack;
+use std::collections::HashSet;
+use tokio::sync::Mutex;
+use tonic::transport::Channel;
+
+/// Check if procurement was closed before the limit
+/// and has nothing left to do
+fn is_archivable(procurement: &Procurement, limit: DateTime<Utc>) -> bool {
+  match (&procurement.status, procurement.closed_at) {
+    (Status::Closed, Some(closed_at)) => {
+      closed_at < limit && !procurement.is_awaiting_pricing() && procurement.outbox.is_empty()
+    }
+    _ => false,
+  }
+}
+
+/// Move closed procurements older than the given days
+/// into the archive store.
+/// Procurements still awaiting pricing or having
+/// undelivered integration events are kept.
+pub async fn archive(
+  procurements: &ProcurementStore,
+  archive: &Mutex<VecPack<Procurement>>,
+  after_days: i64,
+) -> Result<String, String> {
+  let limit = Utc::now() - chrono::Duration::days(after_days);
+  let to_archive = procurements
+    .read()
+    .await
+    .iter()
+    .map(|p| p.unpack())
+    .filter(|p| is_archivable(p, limit))
+    .map(|p| p.id)
+    .collect::<Vec<u32>>();
+
+  let mut count = 0;
+  for procurement_id in to_archive {
+    // Move the latest copy under the procurement lock
+    // Claimed ones are archived on a later run
+    let locked = match procurements.lock(procurement_id).await {
+      Ok(locked) => locked,
+      Err(_) => continue,
+    };
+    // It could have changed since the check
+    if !is_archivable(&locked.procurement, limit) {
+      continue;
+    }
+    archive
+      .lock()
+      .await
+      .insert(locked.procurement.clone())
+      .map_err(|e| e.to_string())?;
+    procurements
+      .remove(locked)
+      .await
+      .map_err(|e| e.to_string())?;
+    count += 1;
+  }
+  Ok(format!("{} procurement archived", count))
+}
+
+/// Rebuild the purchase price index from the
+/// active and archived procurements
+pub async fn rebuild_price_history(
+  procurements: &ProcurementStore,
+  archive: &Mutex<VecPack<Procurement>>,
+  price_history: &Mutex<PriceHistory>,
+) -> Result<String, String> {
+  let procurements = procurements.read().await;
+  let archive = archive.lock().await;
+  let closed = procurements
+    .iter()
+    .chain(archive.iter())
+    .map(|p| p.unpack())
+    .filter(|p| matches!(p.status, Status::Closed))
+    .collect::<Vec<&Procurement>>();
+  *price_history.lock().await = PriceHistory::new(closed.iter().copied());
+  Ok(format!("{} closed procurement indexed", closed.len()))
+}
+
+/// Check that every UPL of the recently closed procurements
+/// exists in the UPL service. Sends UPL mismatch alert
+/// for the procurements with missing UPLs.
+pub async fn reconcile_upls(
+  procurements: &ProcurementStore,
+  client_upl: Downstream<UplClient<Channel>>,
+  notifications: &NotificationCenter,
+  within_days: i64,
+) -> Result<String, String> {
+  let limit = Utc::now() - chrono::Duration::days(within_days);
+  let closed = procurements
+    .read()
+    .await
+    .iter()
+    .map(|p| p.unpack())
+    .filter(|p| match (&p.status, p.closed_at) {
+      (Status::Closed, Some(closed_at)) => closed_at >= limit,
+      _ => false,
+    })
+    .cloned()
+    .collect::<Vec<Procurement>>();
+
+  let mut missing_total = 0;
+  for procurement in &closed {
+    let upl_ids = procurement
+      .upl_candidates
+      .iter()
+      .map(|u| u.upl_id.clone())
+      .collect::<Vec<String>>();
+    if upl_ids.is_empty() {
+      continue;
+    }
+
+    let found = client_upl
+      .call(|mut client| {
+        let upl_ids = upl_ids.clone();
+        async move {
+          let mut found: HashSet<String> = HashSet::new();
+          let mut stream = client.get_bulk(BulkRequest { upl_ids }).await?.into_inner();
+          while let Some(upl) = stream.message().await? {
+            found.insert(upl.id);
+          }
+          Ok(found)
+        }
+      })
+      .await
+      .map_err(|e| e.to_string())?;
+
+    let missing = upl_ids.iter().filter(|id| !found.contains(*id)).count();
+    if missing > 0 {
+      missing_total += missing;
+      notifications
+        .send(
+          AlertEvent::UplCreationMismatch,
+          &[
+            ("procurement_id", procurement.id.to_string()),
+            ("expected", upl_ids.len().to_string()),
+            ("created", found.len().to_string()),
+          ],
+          AlertEvent::UplCreationMismatch.recipients(),
+        )
+        .await;
+    }
+  }
+  Ok(format!(
+    "{} procurement checked, {} UPL missing",
+    closed.len(),
+    missing_total
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn temp_pack<T: packman::VecPackMember>(name: &str) -> VecPack<T> {
+    VecPack::load_or_init(std::env::temp_dir().join(format!(
+      "procurement_test_{}_{}",
+      name,
+      rand::random::<u32>()
+    )))
+    .unwrap()
+  }
+
+  fn closed(id: u32, days_ago: i64) -> Procurement {
+    let mut p = Procurement::new(id, 1, 1, 1);
+    p.status = Status::Closed;
+    p.closed_at = Some(Utc::now() - chrono::Duration::days(days_ago));
+    p
+  }
+
+  #[test]
+  fn test_is_archivable() {
+    let limit = Utc::now() - chrono::Duration::days(30);
+    assert!(is_archivable(&closed(1, 40), limit));
+    assert!(!is_archivable(&closed(1, 10), limit));
+    assert!(!is_archivable(&Procurement::new(1, 1, 1, 1), limit));
+    let mut p = closed(1, 40);
+    p.record_event("procurement.closed");
+    assert!(!is_archivable(&p, limit));
+  }
+
+  #[tokio::test]
+  async fn test_archive() {
+    let mut packs = temp_pack("procurement");
+    packs.insert(closed(1, 40)).unwrap();
+    packs.insert(closed(2, 10)).unwrap();
+    let procurements = ProcurementStore::new(packs);
+    let archived = Mutex::new(temp_pack("procurement_archive"));
+    assert_eq!(
+      archive(&procurements, &archived, 30).await.unwrap(),
+      "1 procurement archived"
+    );
+    assert!(procurements.get(1).await.is_err());
+    assert!(procurements.get(2).await.is_ok());
+    assert!(archived.lock().await.find_id(&1).is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_archive_skips_claimed() {
+    let mut packs = temp_pack("procurement");
+    packs.insert(closed(1, 40)).unwrap();
+    packs.insert(closed(2, 40)).unwrap();
+    let procurements = ProcurementStore::new(packs);
+    let archived = Mutex::new(temp_pack("procurement_archive"));
+    let claim = procurements.claim(1).await.unwrap();
+    assert_eq!(
+      archive(&procurements, &archived, 30).await.unwrap(),
+      "1 procurement archived"
+    );
+    assert!(procurements.get(1).await.is_ok());
+    assert!(procurements.get(2).await.is_err());
+    drop(claim);
+    assert_eq!(
+      archive(&procurements, &archived, 30).await.unwrap(),
+      "1 procurement archived"
+    );
+    assert!(procurements.get(1).await.is_err());
+  }
+}
@@ -0,0 +1,69 @@
+use crate::procurement::UplCandidate;
+use crate::template;
+use gzlib::proto::{pricing::PriceObject, product::SkuObj};
+
+/// Default ZPL label template
+/// Can be overridden by the file in PROCUREMENT_LABEL_TEMPLATE
+pub const DEFAULT_TEMPLATE: &str = "^XA
+^CI28
+^FO30,20^A0N,28,28^FD{{sku_name}}^FS
+^FO30,60^BY2^BCN,80,Y,N,N^FD{{upl_id}}^FS
+^FO30,180^A0N,24,24^FDLejárat: {{best_before}}^FS
+^FO30,215^A0N,32,32^FD{{price}} Ft^FS
+^FO400,215^A0N,24,24^FD{{piece}} {{unit}}^FS
+^XZ
+";
+
+/// Remove ZPL/EPL control characters from a field value
+fn escape(value: &str) -> String {
+  value.replace(&['^', '~'][..], "")
+}
+
+/// Render a label for a UPL candidate
+pub fn render(
+  template: &str,
+  upl: &UplCandidate,
+  sku: Option<&SkuObj>,
+  price: Option<&PriceObject>,
+) -> String {
+  template::render(
+    template,
+    &[
+      ("upl_id", escape(&upl.upl_id)),
+      ("sku", upl.sku.to_string()),
+      (
+        "sku_name",
+        escape(&sku.map(|s| s.display_name.clone()).unwrap_or_default()),
+      ),
+      (
+        "unit",
+        escape(&sku.map(|s| s.unit.clone()).unwrap_or_default()),
+      ),
+      ("piece", upl.get_piece().to_string()),
+      (
+        "best_before",
+        match upl.best_before {
+          Some(bb) => bb.format("%Y-%m-%d").to_string(),
+          None => "-".to_string(),
+        },
+      ),
+      (
+        "price",
+        match price {
+          Some(p) => p.price_gross_retail.to_string(),
+          None => "-".to_string(),
+        },
+      ),
+    ],
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_escape() {
+    assert_eq!(escape("Alma ^FS~JA 1kg"), "Alma FSJA 1kg");
+  }
+}
@@ -1,17 +1,19 @@
 use chrono::{DateTime, Utc};
+use downstream::Downstream;
 use futures_util::stream;
 use gzlib::proto::{
   self,
-  pricing::{pricing_client::PricingClient, GetPriceBulkRequest, PriceObject},
+  pricing::{pricing_client::PricingClient, GetPriceBulkRequest, PriceObject, SetPriceRequest},
   product::{product_client::ProductClient, SkuObj},
+  source::source_client::SourceClient,
   upl::{upl_client::UplClient, UplNew},
 };
 use gzlib::proto::{procurement::procurement_server::*, upl::UplObj};
 use gzlib::proto::{procurement::*, product::GetSkuBulkRequest};
 use packman::*;
-use prelude::{service_address, ServiceError, ServiceResult};
+use prelude::{env_or, ServiceError, ServiceResult};
 use proto::email::{email_client::EmailClient, EmailRequest};
-use std::{env, path::PathBuf};
+use std::{collections::HashSet, env, path::PathBuf, sync::Arc};
 use tokio::sync::{oneshot, Mutex};
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{
@@ -19,68 +21,178 @@ use tonic::{
   Request, Response, Status,
 };
 
+#[cfg(test)]
+mod bench;
+mod change_feed;
+mod downstream;
+mod integration;
+mod jobs;
+mod label;
+mod margin;
+#[cfg(test)]
+mod mock;
+mod notification;
+mod notifier;
+mod overdue;
 mod prelude;
+mod price_history;
+mod price_proposal;
 mod procurement;
+mod purchase_order;
+mod receiving;
+mod scheduler;
+mod store;
+mod template;
+mod upl_id;
+mod webhook;
 
 struct ProcurementService {
-  procurements: Mutex<VecPack<procurement::Procurement>>,
-  client_upl: Mutex<UplClient<Channel>>,
-  client_product: Mutex<ProductClient<Channel>>,
-  client_pricing: Mutex<PricingClient<Channel>>,
-  client_email: Mutex<EmailClient<Channel>>,
+  procurements: Arc<store::ProcurementStore>,
+  // Archived closed procurements
+  archive: Arc<Mutex<VecPack<procurement::Procurement>>>,
+  price_history: Arc<Mutex<price_history::PriceHistory>>,
+  price_proposals: Mutex<VecPack<price_proposal::PriceProposal>>,
+  proposal_claims: price_proposal::ProposalClaims,
+  // Tonic clients are cheap to clone and share the connection,
+  // so every call uses its own clone and calls run concurrently
+  client_upl: Downstream<UplClient<Channel>>,
+  client_product: Downstream<ProductClient<Channel>>,
+  client_pricing: Downstream<PricingClient<Channel>>,
+  client_email: Downstream<EmailClient<Channel>>,
+  // None if SERVICE_ADDR_SOURCE is not set,
+  // purchase orders are not sent then
+  client_source: Option<Downstream<SourceClient<Channel>>>,
+  notifications: Arc<notifier::NotificationCenter>,
+  jobs: scheduler::JobRegistry,
+  changes: Arc<change_feed::ChangeFeed>,
+  // Integration events of the removed procurements
+  integration_outbox: Arc<Mutex<VecPack<procurement::IntegrationEvent>>>,
+  webhooks: Arc<webhook::Webhooks>,
 }
 
 impl ProcurementService {
   // Create new ProcurementService
+  #[allow(clippy::too_many_arguments)]
   fn new(
     db: VecPack<procurement::Procurement>,
-    client_upl: UplClient<Channel>,
-    client_product: ProductClient<Channel>,
-    client_pricing: PricingClient<Channel>,
-    client_email: EmailClient<Channel>,
+    db_archive: VecPack<procurement::Procurement>,
+    db_price_proposal: VecPack<price_proposal::PriceProposal>,
+    db_integration_outbox: VecPack<procurement::IntegrationEvent>,
+    webhooks: Arc<webhook::Webhooks>,
+    client_upl: Downstream<UplClient<Channel>>,
+    client_product: Downstream<ProductClient<Channel>>,
+    client_pricing: Downstream<PricingClient<Channel>>,
+    client_email: Downstream<EmailClient<Channel>>,
+    client_source: Option<Downstream<SourceClient<Channel>>>,
+    notifications: Arc<notifier::NotificationCenter>,
+    jobs: scheduler::JobRegistry,
   ) -> Self {
+    // Build purchase price index from the closed procurements
+    let price_history =
+      price_history::PriceHistory::new(db.iter().chain(db_archive.iter()).map(|p| p.unpack()));
     Self {
-      procurements: Mutex::new(db),
-      client_upl: Mutex::new(client_upl),
-      client_product: Mutex::new(client_product),
-      client_pricing: Mutex::new(client_pricing),
-      client_email: Mutex::new(client_email),
+      procurements: Arc::new(store::ProcurementStore::new(db)),
+      archive: Arc::new(Mutex::new(db_archive)),
+      price_history: Arc::new(Mutex::new(price_history)),
+      price_proposals: Mutex::new(db_price_proposal),
+      proposal_claims: price_proposal::ProposalClaims::default(),
+      client_upl,
+      client_product,
+      client_pricing,
+      client_email,
+      client_source,
+      notifications,
+      jobs,
+      changes: Arc::new(change_feed::ChangeFeed::new(env_or(
+        "PROCUREMENT_WATCH_BUFFER",
+        10000,
+      ))),
+      integration_outbox: Arc::new(Mutex::new(db_integration_outbox)),
+      webhooks,
     }
   }
 
-  /// Calculate the next procurement ID
-  async fn next_id(&self) -> u32 {
-    let mut last_id = 0;
-    self.procurements.lock().await.iter().for_each(|proc| {
-      let proc_id = proc.unpack().id;
-      if proc_id > last_id {
-        last_id = proc_id;
-      }
-    });
-    last_id + 1
+  /// Apply a change to a procurement under its own lock
+  /// Bumps its version and publishes the change event
+  async fn update<F>(
+    &self,
+    procurement_id: u32,
+    kind: ChangeKind,
+    f: F,
+  ) -> ServiceResult<procurement::Procurement>
+  where
+    F: FnOnce(&mut procurement::Procurement) -> procurement::ProcResult<&procurement::Procurement>,
+  {
+    let locked = self.procurements.lock(procurement_id).await?;
+    self.apply(locked, kind, f).await
+  }
+
+  /// Apply a change to a locked procurement
+  /// The change event is published before the lock is released,
+  /// so the events of a procurement keep their order
+  async fn apply<F>(
+    &self,
+    mut locked: store::Locked<'_>,
+    kind: ChangeKind,
+    f: F,
+  ) -> ServiceResult<procurement::Procurement>
+  where
+    F: FnOnce(&mut procurement::Procurement) -> procurement::ProcResult<&procurement::Procurement>,
+  {
+    f(&mut locked.procurement).map_err(|e| ServiceError::bad_request(&e))?;
+    let res = change_feed::record(&mut locked.procurement, kind);
+    locked.save().await?;
+    self.changes.publish(kind, &res).await;
+    Ok(res)
   }
 
   /// Create a new procurement
   async fn create_new(&self, r: CreateNewRequest) -> ServiceResult<ProcurementObject> {
-    // Create the new procurement object
-    let new_procurement =
-      procurement::Procurement::new(self.next_id().await, r.source_id, r.created_by);
+    // Stock ID 0 means the configured default stock
+    let stock_id = match r.stock_id {
+      0 => env_or("PROCUREMENT_DEFAULT_STOCK_ID", 1),
+      x => x,
+    };
+
+    // Create and store the new procurement object
+    // Write lock is held, so the new ID cannot be taken meanwhile
+    let new_procurement = {
+      let mut procurements = self.procurements.write().await;
+
+      // Calculate the next procurement ID
+      // Archived IDs are never reused
+      let next_id = procurements
+        .iter()
+        .chain(self.archive.lock().await.iter())
+        .map(|p| p.unpack().id)
+        .max()
+        .unwrap_or(0)
+        + 1;
+
+      let mut new_procurement =
+        procurement::Procurement::new(next_id, r.source_id, stock_id, r.created_by);
+      let new_procurement = change_feed::record(&mut new_procurement, ChangeKind::Created);
+      procurements.insert(new_procurement.clone())?;
+      new_procurement
+    };
 
-    // Store new procurement
     self
-      .procurements
-      .lock()
-      .await
-      .insert(new_procurement.clone())?;
+      .changes
+      .publish(ChangeKind::Created, &new_procurement)
+      .await;
 
     // Return procurement as ProcurementObject
     Ok(new_procurement.into())
   }
 
   /// Get procurement by ID
+  /// Falls back to the archive
   async fn get_by_id(&self, r: GetByIdRequest) -> ServiceResult<ProcurementObject> {
+    if let Ok(p) = self.procurements.get(r.procurement_id).await {
+      return Ok(p.into());
+    }
     let res = self
-      .procurements
+      .archive
       .lock()
       .await
       .find_id(&r.procurement_id)?
@@ -91,28 +203,45 @@ impl ProcurementService {
   }
 
   /// Get all procurement IDs
+  /// Archived procurements are included
   async fn get_all(&self) -> ServiceResult<Vec<u32>> {
-    let res = self
-      .procurements
-      .lock()
-      .await
+    let procurements = self.procurements.read().await;
+    let archive = self.archive.lock().await;
+    let res = procurements
+      .iter()
+      .chain(archive.iter())
+      .map(|p| p.unpack().id)
+      .collect::<Vec<u32>>();
+    Ok(res)
+  }
+
+  /// Get procurement IDs of a destination stock
+  /// Archived procurements are included
+  async fn get_all_by_stock(&self, r: GetAllByStockRequest) -> ServiceResult<Vec<u32>> {
+    let procurements = self.procurements.read().await;
+    let archive = self.archive.lock().await;
+    let res = procurements
       .iter()
+      .chain(archive.iter())
+      .filter(|p| p.unpack().stock_id == r.stock_id)
       .map(|p| p.unpack().id)
       .collect::<Vec<u32>>();
     Ok(res)
   }
 
   /// Get info bulk
+  /// Archived procurements are included
   async fn get_info_bulk(
     &self,
     r: GetInfoBulkRequest,
   ) -> ServiceResult<Vec<ProcurementInfoObject>> {
-    let res = self
-      .procurements
-      .lock()
-      .await
+    let procurements = self.procurements.read().await;
+    let archive = self.archive.lock().await;
+    let res = procurements
       .iter()
+      .chain(archive.iter())
       .filter(|p| r.procurement_ids.contains(&p.unpack().id))
+      .filter(|p| r.stock_id == 0 || p.unpack().stock_id == r.stock_id)
       .map(|p| p.unpack().clone().into())
       .collect::<Vec<ProcurementInfoObject>>();
     Ok(res)
@@ -134,14 +263,23 @@ impl ProcurementService {
 
     // Try to set delivery
     let res = self
-      .procurements
-      .lock()
-      .await
-      .find_id_mut(&r.procurement_id)?
-      .as_mut()
-      .unpack()
-      .set_delivery_date(ddate)
-      .clone();
+      .update(r.procurement_id, ChangeKind::Updated, |p| {
+        Ok(p.set_delivery_date(ddate))
+      })
+      .await?;
+
+    // Return self as ProcurementObject
+    Ok(res.into())
+  }
+
+  /// Try set destination stock
+  async fn set_stock(&self, r: SetStockRequest) -> ServiceResult<ProcurementObject> {
+    // Try to set stock ID
+    let res = self
+      .update(r.procurement_id, ChangeKind::Updated, |p| {
+        p.set_stock_id(r.stock_id)
+      })
+      .await?;
 
     // Return self as ProcurementObject
     Ok(res.into())
@@ -151,20 +289,68 @@ impl ProcurementService {
   async fn set_reference(&self, r: SetReferenceRequest) -> ServiceResult<ProcurementObject> {
     // Try to set reference
     let res = self
-      .procurements
-      .lock()
-      .await
-      .find_id_mut(&r.procurement_id)?
-      .as_mut()
-      .unpack()
-      .set_reference(r.reference)
-      .clone();
+      .update(r.procurement_id, ChangeKind::Updated, |p| {
+        Ok(p.set_reference(r.reference))
+      })
+      .await?;
 
     // Return self as ProcurementObject
     Ok(res.into())
   }
 
+  /// Try set supplier invoice
+  async fn set_invoice(&self, r: SetInvoiceRequest) -> ServiceResult<ProcurementObject> {
+    // Process invoice date
+    let idate: Option<DateTime<Utc>> = match r.invoice_date.len() {
+      // If a not empty string, then try to parse as rfc3339
+      x if x > 0 => {
+        let date = DateTime::parse_from_rfc3339(&r.invoice_date)
+          .map_err(|_| ServiceError::bad_request("A megadott számla dátum hibás!"))?;
+        Some(date.with_timezone(&Utc))
+      }
+      // If empty string then None
+      _ => None,
+    };
+
+    // Process due date
+    let ddate: Option<DateTime<Utc>> = match r.due_date.len() {
+      // If a not empty string, then try to parse as rfc3339
+      x if x > 0 => {
+        let date = DateTime::parse_from_rfc3339(&r.due_date)
+          .map_err(|_| ServiceError::bad_request("A megadott fizetési határidő hibás!"))?;
+        Some(date.with_timezone(&Utc))
+      }
+      // If empty string then None
+      _ => None,
+    };
+
+    // Try to set invoice
+    let res = self
+      .update(r.procurement_id, ChangeKind::Updated, |p| {
+        p.set_invoice(r.invoice_number, idate, ddate)
+      })
+      .await?;
+
+    // Return procurement as ProcurementObject
+    Ok(res.into())
+  }
+
+  /// Try set invoiced amount and price for a SKU
+  async fn set_invoice_item(&self, r: SetInvoiceItemRequest) -> ServiceResult<ProcurementObject> {
+    // Try to set invoice item
+    let res = self
+      .update(r.procurement_id, ChangeKind::Updated, |p| {
+        p.invoice_item_set(r.sku, r.invoiced_amount, r.invoiced_net_price)
+      })
+      .await?;
+
+    // Return procurement as ProcurementObject
+    Ok(res.into())
+  }
+
   /// Try to add SKU
+  /// Returns warnings if the given price deviates strongly
+  /// from the last purchase price
   async fn add_sku(&self, r: AddSkuRequest) -> ServiceResult<ProcurementObject> {
     // Try to get SKU object
     let sku_object = r.sku.ok_or(ServiceError::internal_error(
@@ -173,37 +359,80 @@ impl ProcurementService {
 
     // Try to add SKU
     let res = self
-      .procurements
+      .update(r.procurement_id, ChangeKind::ItemChanged, |p| {
+        p.sku_add(
+          sku_object.sku,
+          sku_object.ordered_amount,
+          sku_object.expected_net_price,
+        )
+      })
+      .await?;
+
+    // Compare price to the last purchase price
+    let mut warnings: Vec<String> = Vec::new();
+    if let Some(last) = self
+      .price_history
       .lock()
       .await
-      .find_id_mut(&r.procurement_id)?
-      .as_mut()
-      .unpack()
-      .sku_add(
-        sku_object.sku,
-        sku_object.ordered_amount,
+      .last_purchase(sku_object.sku, res.source_id)
+    {
+      if procurement::exceeds_tolerance(
+        last.net_price,
         sku_object.expected_net_price,
-      )
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+        env_or("PROCUREMENT_PRICE_HISTORY_DEVIATION_PERCENT", 20),
+      ) {
+        warnings.push(format!(
+          "Figyelem! A megadott ár ({} Ft) jelentősen eltér az utolsó beszerzési ártól ({} Ft, {}, beszerzés #{})",
+          sku_object.expected_net_price,
+          last.net_price,
+          last.date.format("%Y-%m-%d"),
+          last.procurement_id
+        ));
+      }
+    }
 
-    // Return procurement as ProcurementObject
-    Ok(res.into())
+    // Return procurement as ProcurementObject with warnings
+    let mut res: ProcurementObject = res.into();
+    res.warnings = warnings;
+    Ok(res)
+  }
+
+  /// Get purchase price history of a SKU
+  /// source_id 0 means any supplier
+  async fn get_price_history(
+    &self,
+    r: GetPriceHistoryRequest,
+  ) -> ServiceResult<Vec<PriceHistoryObject>> {
+    let source_id = match r.source_id {
+      0 => None,
+      x => Some(x),
+    };
+    let res = self
+      .price_history
+      .lock()
+      .await
+      .get(r.sku, source_id)
+      .into_iter()
+      .map(|i| PriceHistoryObject {
+        sku: i.sku,
+        source_id: i.source_id,
+        procurement_id: i.procurement_id,
+        date: i.date.to_rfc3339(),
+        net_price: i.net_price,
+        amount: i.amount,
+      })
+      .collect::<Vec<PriceHistoryObject>>();
+    Ok(res)
   }
 
   /// Try to remove SKU
   async fn remove_sku(&self, r: RemoveSkuRequest) -> ServiceResult<ProcurementObject> {
     // Try to remove SKU
     let res = self
-      .procurements
-      .lock()
-      .await
-      .find_id_mut(&r.procurement_id)?
-      .as_mut()
-      .unpack()
-      .sku_remove(r.sku)
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+      .update(r.procurement_id, ChangeKind::ItemChanged, |p| {
+        p.sku_remove(r.sku)
+      })
+      .await?;
 
     // Return procurement as ProcurementObject
     Ok(res.into())
@@ -213,15 +442,10 @@ impl ProcurementService {
   async fn set_sku_piece(&self, r: SetSkuPieceRequest) -> ServiceResult<ProcurementObject> {
     // Try to set SKU piece
     let res = self
-      .procurements
-      .lock()
-      .await
-      .find_id_mut(&r.procurement_id)?
-      .as_mut()
-      .unpack()
-      .sku_update_amount(r.sku, r.piece)
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+      .update(r.procurement_id, ChangeKind::ItemChanged, |p| {
+        p.sku_update_amount(r.sku, r.piece)
+      })
+      .await?;
 
     // Return procurement as ProcurementObject
     Ok(res.into())
@@ -231,20 +455,78 @@ impl ProcurementService {
   async fn set_sku_price(&self, r: SetSkuPriceRequest) -> ServiceResult<ProcurementObject> {
     // Try to set SKU price
     let res = self
-      .procurements
-      .lock()
-      .await
-      .find_id_mut(&r.procurement_id)?
-      .as_mut()
-      .unpack()
-      .sku_update_price(r.sku, r.expected_net_price)
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+      .update(r.procurement_id, ChangeKind::ItemChanged, |p| {
+        p.sku_update_price(r.sku, r.expected_net_price)
+      })
+      .await?;
+
+    // Return procurement as ProcurementObject
+    Ok(res.into())
+  }
+
+  /// Try to set SKU actual (received) price
+  /// 0 means to clear the actual price
+  async fn set_sku_actual_price(
+    &self,
+    r: SetSkuActualPriceRequest,
+  ) -> ServiceResult<ProcurementObject> {
+    let actual_price = match r.actual_net_price {
+      0 => None,
+      x => Some(x),
+    };
+
+    // Try to set SKU actual price
+    let res = self
+      .update(r.procurement_id, ChangeKind::ItemChanged, |p| {
+        p.sku_update_actual_price(r.sku, actual_price)
+      })
+      .await?;
 
     // Return procurement as ProcurementObject
     Ok(res.into())
   }
 
+  /// Get price variance report
+  /// Lists the items whose actual price differs from the expected one
+  /// If no procurement ID is given, all procurements are checked
+  /// Items without expected price are flagged instead of a percent
+  async fn get_price_variance(
+    &self,
+    r: GetPriceVarianceRequest,
+  ) -> ServiceResult<Vec<PriceVarianceObject>> {
+    let percent = env_or("PROCUREMENT_PRICE_VARIANCE_PERCENT", 5);
+    let res = self
+      .procurements
+      .read()
+      .await
+      .iter()
+      .filter(|p| r.procurement_ids.is_empty() || r.procurement_ids.contains(&p.unpack().id))
+      .flat_map(|p| {
+        let p = p.unpack();
+        p.price_variance(percent)
+          .into_iter()
+          .map(|item| {
+            let actual = item.get_net_price();
+            PriceVarianceObject {
+              procurement_id: p.id,
+              source_id: p.source_id,
+              sku: item.sku,
+              expected_net_price: item.expected_net_price,
+              actual_net_price: actual,
+              // No percent without an expected price
+              variance_percent: match item.expected_net_price {
+                0 => 0.0,
+                x => (actual as f64 - x as f64) / x as f64 * 100.0,
+              },
+              missing_expected_price: item.expected_net_price == 0,
+            }
+          })
+          .collect::<Vec<PriceVarianceObject>>()
+      })
+      .collect::<Vec<PriceVarianceObject>>();
+    Ok(res)
+  }
+
   /// Try to add UPL
   async fn add_upl(&self, r: AddUplRequest) -> ServiceResult<ProcurementObject> {
     let upl_candidate = r.upl_candidate.ok_or(ServiceError::internal_error(
@@ -263,27 +545,243 @@ impl ProcurementService {
       _ => None,
     };
 
-    let res = self
-      .procurements
-      .lock()
-      .await
-      .find_id_mut(&r.procurement_id)?
-      .as_mut()
-      .unpack()
-      .upl_add(
-        upl_candidate.upl_id,
-        upl_candidate.sku,
-        upl_candidate.upl_piece,
-        upl_candidate.opened_sku,
-        bdate,
-      )
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+    let res = {
+      let mut locked = self.procurements.lock(r.procurement_id).await?;
+      // UPL ID reserved by another procurement cannot be used
+      // Checked and saved under the write lock
+      let mut procurements = self.procurements.write().await;
+      if !locked
+        .reserved_elsewhere(&procurements, std::slice::from_ref(&upl_candidate.upl_id))
+        .is_empty()
+      {
+        return Err(ServiceError::bad_request(store::RESERVED_ELSEWHERE));
+      }
+      locked
+        .procurement
+        .upl_add(
+          upl_candidate.upl_id,
+          upl_candidate.sku,
+          upl_candidate.upl_piece,
+          upl_candidate.opened_sku,
+          bdate,
+          // 0 means the procurement stock
+          match upl_candidate.stock_id {
+            0 => None,
+            x => Some(x),
+          },
+        )
+        .map_err(|e| ServiceError::bad_request(&e))?;
+      let res = change_feed::record(&mut locked.procurement, ChangeKind::UplChanged);
+      locked.save_to(&mut procurements)?;
+      drop(procurements);
+      self.changes.publish(ChangeKind::UplChanged, &res).await;
+      res
+    };
 
     // Return procurement as ProcurementObject
     Ok(res.into())
   }
 
+  /// Try to add UPL candidates in bulk
+  /// All messages must refer to the same procurement;
+  /// atomic flag is taken from the first message
+  async fn add_upl_bulk(&self, r: Vec<AddUplBulkRequest>) -> ServiceResult<AddUplBulkResponse> {
+    let (procurement_id, atomic) = match r.first() {
+      Some(first) => (first.procurement_id, first.atomic),
+      None => return Err(ServiceError::bad_request("Nincs rögzítendő UPL!")),
+    };
+
+    if r.iter().any(|i| i.procurement_id != procurement_id) {
+      return Err(ServiceError::bad_request(
+        "Egy kérésben csak egy beszerzés UPL-jei rögzíthetők!",
+      ));
+    }
+
+    // Create candidates, it checks UPL ID and best_before
+    let candidates = r
+      .into_iter()
+      .map(|i| {
+        let upl_candidate = i.upl_candidate.unwrap_or_default();
+        let upl_id = upl_candidate.upl_id.clone();
+        let candidate = match upl_candidate.best_before.len() {
+          // If a not empty string, then try to parse as rfc3339
+          x if x > 0 => DateTime::parse_from_rfc3339(&upl_candidate.best_before)
+            .map(|date| Some(date.with_timezone(&Utc)))
+            .map_err(|_| "A megadott lejárati dátum hibás!".to_string()),
+          // If empty string then None
+          _ => Ok(None),
+        }
+        .and_then(|bdate| {
+          procurement::UplCandidate::new(
+            upl_candidate.upl_id,
+            upl_candidate.sku,
+            upl_candidate.upl_piece,
+            upl_candidate.opened_sku,
+            bdate,
+            // 0 means the procurement stock
+            match upl_candidate.stock_id {
+              0 => None,
+              x => Some(x),
+            },
+          )
+        });
+        (upl_id, candidate)
+      })
+      .collect::<Vec<(String, procurement::ProcResult<procurement::UplCandidate>)>>();
+
+    let upl_ids = candidates
+      .iter()
+      .map(|(upl_id, _)| upl_id.clone())
+      .collect::<Vec<String>>();
+
+    let (results, res) = {
+      let mut locked = self.procurements.lock(procurement_id).await?;
+      // UPL IDs reserved by another procurement cannot be used
+      // Checked and saved under the write lock
+      let mut procurements = self.procurements.write().await;
+      let reserved = locked.reserved_elsewhere(&procurements, &upl_ids);
+      let candidates = candidates
+        .into_iter()
+        .map(|(upl_id, candidate)| match reserved.contains(&upl_id) {
+          true => (upl_id, Err(store::RESERVED_ELSEWHERE.to_string())),
+          false => (upl_id, candidate),
+        })
+        .collect::<Vec<(String, procurement::ProcResult<procurement::UplCandidate>)>>();
+      let results = locked.procurement.upl_add_bulk(candidates, atomic);
+      // Only record and publish if anything was added
+      match results.iter().any(|(_, r)| r.is_ok()) {
+        true => {
+          let res = change_feed::record(&mut locked.procurement, ChangeKind::UplChanged);
+          locked.save_to(&mut procurements)?;
+          drop(procurements);
+          self.changes.publish(ChangeKind::UplChanged, &res).await;
+          (results, res)
+        }
+        false => (results, locked.procurement.clone()),
+      }
+    };
+
+    // Compact summary instead of the whole procurement
+    Ok(AddUplBulkResponse {
+      procurement_id,
+      added_count: results.iter().filter(|(_, r)| r.is_ok()).count() as u32,
+      failed_count: results.iter().filter(|(_, r)| r.is_err()).count() as u32,
+      upl_count: res
+        .upl_candidates
+        .iter()
+        .fold(0, |acc, c| acc + c.get_piece()),
+      results: results
+        .into_iter()
+        .map(|(upl_id, r)| AddUplResult {
+          upl_id,
+          ok: r.is_ok(),
+          error: r.err().unwrap_or_default(),
+        })
+        .collect::<Vec<AddUplResult>>(),
+    })
+  }
+
+  /// Replay a batch of offline scanner operations
+  /// Operations are applied in the order they were sent,
+  /// results are returned in the incoming order
+  async fn sync_scan_batch(&self, r: SyncScanBatchRequest) -> ServiceResult<SyncScanBatchResponse> {
+    // Parse operations, keep the original position
+    let mut operations: Vec<(usize, String, procurement::SyncOperation)> = Vec::new();
+    let mut results: Vec<Option<ScanOperationResult>> = vec![None; r.operations.len()];
+    for (index, op) in r.operations.into_iter().enumerate() {
+      let op_id = op.op_id.clone();
+      match receiving::parse_operation(op) {
+        Ok(operation) => operations.push((index, op_id, operation)),
+        Err(e) => {
+          results[index] = Some(ScanOperationResult {
+            op_id,
+            outcome: ScanOperationOutcome::Rejected as i32,
+            message: e,
+          })
+        }
+      }
+    }
+
+    let upl_ids = operations
+      .iter()
+      .filter_map(|(_, _, operation)| match operation {
+        procurement::SyncOperation::Add(c) => Some(c.upl_id.clone()),
+        _ => None,
+      })
+      .collect::<Vec<String>>();
+
+    // Operations are replayed in the order the device recorded them
+    let version = {
+      let mut locked = self.procurements.lock(r.procurement_id).await?;
+
+      if let procurement::Status::Closed = locked.procurement.status {
+        return Err(ServiceError::bad_request(
+          "Lezárt beszerzés nem szinkronizálható!",
+        ));
+      }
+
+      // UPL IDs reserved by another procurement cannot be added
+      // Checked and saved under the write lock
+      let mut procurements = self.procurements.write().await;
+      let reserved = locked.reserved_elsewhere(&procurements, &upl_ids);
+      operations.retain(|(index, op_id, operation)| match operation {
+        procurement::SyncOperation::Add(c) if reserved.contains(&c.upl_id) => {
+          results[*index] = Some(ScanOperationResult {
+            op_id: op_id.clone(),
+            outcome: ScanOperationOutcome::Rejected as i32,
+            message: store::RESERVED_ELSEWHERE.to_string(),
+          });
+          false
+        }
+        _ => true,
+      });
+
+      // Drop the old removals kept for conflict resolution
+      let tombstone_days = env_or("PROCUREMENT_SYNC_TOMBSTONE_DAYS", 7);
+      locked
+        .procurement
+        .prune_removed_upls(Utc::now() - chrono::Duration::days(tombstone_days));
+
+      let mut changed = false;
+      for (index, op_id, operation) in operations {
+        let outcome = locked.procurement.upl_sync(operation, r.base_version);
+        if let procurement::SyncOutcome::Applied = outcome {
+          changed = true;
+        }
+        let (outcome, message) = match outcome {
+          procurement::SyncOutcome::Applied => (ScanOperationOutcome::Applied, "".to_string()),
+          procurement::SyncOutcome::AlreadyApplied => {
+            (ScanOperationOutcome::AlreadyApplied, "".to_string())
+          }
+          procurement::SyncOutcome::Skipped(msg) => (ScanOperationOutcome::Skipped, msg),
+          procurement::SyncOutcome::Rejected(msg) => (ScanOperationOutcome::Rejected, msg),
+        };
+        results[index] = Some(ScanOperationResult {
+          op_id,
+          outcome: outcome as i32,
+          message,
+        });
+      }
+
+      // Record and publish one change event for the whole batch
+      if changed {
+        let res = change_feed::record(&mut locked.procurement, ChangeKind::UplChanged);
+        locked.save_to(&mut procurements)?;
+        drop(procurements);
+        self.changes.publish(ChangeKind::UplChanged, &res).await;
+      }
+      locked.procurement.version
+    };
+
+    // Device sends the returned version as base of the next batch
+    Ok(SyncScanBatchResponse {
+      procurement_id: r.procurement_id,
+      device_id: r.device_id,
+      results: results.into_iter().flatten().collect(),
+      version,
+    })
+  }
+
   /// Try update UPL
   async fn update_upl(&self, r: UpdateUplRequest) -> ServiceResult<ProcurementObject> {
     // Process bestbefore date
@@ -299,15 +797,17 @@ impl ProcurementService {
     };
 
     let res = self
-      .procurements
-      .lock()
-      .await
-      .find_id_mut(&r.procurement_id)?
-      .as_mut()
-      .unpack()
-      .upl_update_all(&r.upl_id, r.sku, r.piece, bdate)
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+      .update(r.procurement_id, ChangeKind::UplChanged, |p| {
+        p.upl_update_all(&r.upl_id, r.sku, r.piece, bdate)?;
+        // Stock override is kept unless a new one is given,
+        // or it is cleared explicitly to use the procurement stock
+        match (r.clear_stock, r.stock_id) {
+          (true, _) => p.upl_update_stock(&r.upl_id, None),
+          (false, 0) => Ok(&*p),
+          (false, stock_id) => p.upl_update_stock(&r.upl_id, Some(stock_id)),
+        }
+      })
+      .await?;
 
     // Return procurement as ProcurementObject
     Ok(res.into())
@@ -317,15 +817,10 @@ impl ProcurementService {
   async fn remove_upl(&self, r: RemoveUplRequest) -> ServiceResult<ProcurementObject> {
     // Try to remove UPL candidate
     let res = self
-      .procurements
-      .lock()
-      .await
-      .find_id_mut(&r.procurement_id)?
-      .as_mut()
-      .unpack()
-      .upl_remove(r.upl_id)
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+      .update(r.procurement_id, ChangeKind::UplChanged, |p| {
+        p.upl_remove(r.upl_id)
+      })
+      .await?;
 
     // Return procurement as ProcurementObject
     Ok(res.into())
@@ -335,260 +830,913 @@ impl ProcurementService {
   /// Only with Status::New
   async fn remove_procurement(&self, r: RemoveRequest) -> ServiceResult<()> {
     // Check if procurement exists and can be removed
-    let can_remove: bool = self
-      .procurements
-      .lock()
-      .await
-      .find_id(&r.procurement_id)?
-      .get(|p| {
-        if let procurement::Status::New = p.status {
-          return true;
-        }
-        false
-      });
+    let mut locked = self.procurements.lock(r.procurement_id).await?;
 
     // Try to remove as Pack
-    if can_remove {
+    if let procurement::Status::New = locked.procurement.status {
+      // Removed procurement cannot hold its integration events,
+      // so they are moved to the standalone outbox first
+      let procurement = change_feed::record(&mut locked.procurement, ChangeKind::Removed);
+      {
+        let mut outbox = self.integration_outbox.lock().await;
+        for event in &procurement.outbox {
+          outbox.insert(event.clone())?;
+        }
+      }
+      self.procurements.remove(locked).await?;
       self
-        .procurements
-        .lock()
-        .await
-        .remove_pack(&r.procurement_id)?;
+        .changes
+        .publish(ChangeKind::Removed, &procurement)
+        .await;
     }
 
     // Returns Ok(())
     Ok(())
   }
 
-  async fn try_close(&self, id: u32) -> ServiceResult<()> {
-    if let Ok(procurement) = self.procurements.lock().await.find_id_mut(&id) {
-      // 1. Check if status is Processing
-      match procurement.unpack().status {
-        procurement::Status::Processing => (),
-        _ => {
-          return Err(ServiceError::bad_request(
-            "A beszerzés nem zárható le! A státusz nem Feldolgozás alatt.",
-          ))
+  /// Load existing UplObjs for the given UPL IDs
+  async fn load_upls(&self, upl_ids: Vec<String>) -> ServiceResult<Vec<UplObj>> {
+    self
+      .client_upl
+      .call(|mut client| {
+        let upl_ids = upl_ids.clone();
+        async move {
+          let mut all_upls: Vec<UplObj> = Vec::new();
+
+          let mut all_upl_stream = client
+            .get_bulk(gzlib::proto::upl::BulkRequest { upl_ids })
+            .await?
+            .into_inner();
+
+          while let Some(upl_obj) = all_upl_stream.message().await? {
+            all_upls.push(upl_obj);
+          }
+
+          Ok(all_upls)
         }
-      }
+      })
+      .await
+  }
 
-      // 2. Check if all new UPL IDS are not already taken
-      let new_upl_ids = procurement
-        .unpack()
-        .upl_candidates
-        .iter()
-        .map(|u| u.upl_id.clone())
-        .collect::<Vec<String>>();
+  /// Collect every UPL ID used or reserved by any procurement
+  async fn taken_upl_ids(&self) -> HashSet<String> {
+    let mut res: HashSet<String> = HashSet::new();
+    let procurements = self.procurements.read().await;
+    let archive = self.archive.lock().await;
+    procurements.iter().chain(archive.iter()).for_each(|p| {
+      let p = p.unpack();
+      p.upl_candidates.iter().for_each(|u| {
+        res.insert(u.upl_id.clone());
+      });
+      p.reserved_upl_ids.iter().for_each(|id| {
+        res.insert(id.clone());
+      });
+    });
+    res
+  }
 
-      let mut all_upls: Vec<UplObj> = Vec::new();
+  /// Try to reserve new Luhn valid UPL IDs for a procurement
+  /// IDs are unique among the procurements and not used by the UPL service
+  async fn reserve_upl_ids(&self, r: ReserveUplIdsRequest) -> ServiceResult<Vec<String>> {
+    if r.count == 0 || r.count > 1000 {
+      return Err(ServiceError::bad_request(
+        "Egyszerre 1 és 1000 közötti UPL azonosító foglalható!",
+      ));
+    }
 
-      let mut all_upl_stream = self
-        .client_upl
-        .lock()
-        .await
-        .get_bulk(gzlib::proto::upl::BulkRequest {
-          upl_ids: new_upl_ids,
-        })
-        .await
-        .map_err(|e| ServiceError::bad_request(&e.to_string()))?
-        .into_inner();
-
-      while let Some(upl_obj) = all_upl_stream
-        .message()
-        .await
-        .map_err(|e| ServiceError::bad_request(&e.to_string()))?
-      {
-        all_upls.push(upl_obj);
-      }
+    // Check if procurement exists and not closed
+    if let procurement::Status::Closed = self.procurements.get(r.procurement_id).await?.status {
+      return Err(ServiceError::bad_request(
+        "Lezárt beszerzéshez nem foglalható UPL azonosító!",
+      ));
+    }
 
-      // If there is any found UPL with a new ID, then return error!
-      if all_upls.len() > 0 {
-        return Err(
-          ServiceError::bad_request(&format!(
-            "A beszerzés nem zárható le. Az alábbi UPL azonosítók már használatban vannak: {:?}",
-            all_upls.into_iter().map(|u| u.id).collect::<Vec<String>>(),
-          ))
-          .into(),
-        );
+    let id_length = upl_id::length_from_env().map_err(|e| ServiceError::internal_error(&e))?;
+    let mut taken = self.taken_upl_ids().await;
+    let mut res: Vec<String> = Vec::new();
+
+    // Generate until all IDs are free in the UPL service as well
+    while res.len() < r.count as usize {
+      let candidates = upl_id::generate_unique(r.count as usize - res.len(), id_length, &taken)
+        .map_err(|e| ServiceError::internal_error(&e))?;
+      let used = self
+        .load_upls(candidates.clone())
+        .await?
+        .into_iter()
+        .map(|u| u.id)
+        .collect::<Vec<String>>();
+      for candidate in candidates {
+        if !used.contains(&candidate) {
+          res.push(candidate.clone());
+        }
+        taken.insert(candidate);
       }
+    }
 
-      // Collect SKU IDs
-      let sku_id = procurement
-        .unpack()
-        .items
-        .iter()
-        .map(|i| i.sku)
-        .collect::<Vec<u32>>();
-
-      // Load SKU objects to access SKU and product data
-      let mut all_skus = self
-        .client_product
-        .lock()
-        .await
-        .get_sku_bulk(GetSkuBulkRequest {
-          sku_id: sku_id.clone(),
-        })
-        .await
-        .map_err(|e| ServiceError::bad_request(&e.to_string()))?
-        .into_inner();
-
-      let mut sku_objects: Vec<SkuObj> = Vec::new();
-
-      while let Some(sku_obj) = all_skus
-        .message()
-        .await
-        .map_err(|e| ServiceError::bad_request(&e.to_string()))?
-      {
-        sku_objects.push(sku_obj);
+    // Store reservation
+    let mut locked = self.procurements.lock(r.procurement_id).await?;
+    locked
+      .procurement
+      .reserve_upl_ids(res.clone())
+      .map_err(|e| ServiceError::bad_request(&e))?;
+    let procurement = change_feed::record(&mut locked.procurement, ChangeKind::UplChanged);
+    {
+      // Recheck taken IDs under the write lock,
+      // as procurements could change meanwhile
+      let mut procurements = self.procurements.write().await;
+      if procurements.iter().any(|p| {
+        let p = p.unpack();
+        p.upl_candidates.iter().any(|u| res.contains(&u.upl_id))
+          || p.reserved_upl_ids.iter().any(|id| res.contains(id))
+      }) {
+        return Err(ServiceError::internal_error(
+          "UPL azonosító ütközés! Kérem próbálja újra!",
+        ));
       }
+      locked.save_to(&mut procurements)?;
+    }
 
-      // Load PriceObjects to access SKU price data
-      let mut all_prices = self
-        .client_pricing
-        .lock()
-        .await
-        .get_price_bulk(GetPriceBulkRequest { skus: sku_id })
-        .await
-        .map_err(|e| ServiceError::bad_request(&e.to_string()))?
-        .into_inner();
-
-      let mut price_objects: Vec<PriceObject> = Vec::new();
+    self
+      .changes
+      .publish(ChangeKind::UplChanged, &procurement)
+      .await;
 
-      while let Some(price_obj) = all_prices
-        .message()
-        .await
-        .map_err(|e| ServiceError::bad_request(&e.to_string()))?
-      {
-        price_objects.push(price_obj);
-      }
+    Ok(res)
+  }
 
-      // Create empty result vector
-      let mut result_upl_candidates: Vec<UplNew> = Vec::new();
+  /// Create UPLs, returns the created UPL IDs
+  /// Not retried, as a failed call could have created UPLs already.
+  /// Creating many UPLs takes long, so it has its own deadline
+  async fn create_upls(&self, upls: Vec<UplNew>) -> Result<Vec<String>, downstream::OnceError> {
+    let deadline =
+      std::time::Duration::from_secs(env_or("PROCUREMENT_UPL_CREATE_TIMEOUT_SECS", 120));
+    let request = Request::new(stream::iter(upls));
+    let res = self
+      .client_upl
+      .call_once_within(deadline, |mut client| async move {
+        client.create_new_bulk(request).await
+      })
+      .await?;
+    Ok(res.into_inner().upl_ids)
+  }
 
-      for sku_item in procurement.unpack().items.iter() {
-        // Try find related SKU object
-        let sku_obj =
-          sku_objects
-            .iter()
-            .find(|so| so.sku == sku_item.sku)
-            .ok_or(ServiceError::bad_request(
-              "A beszerzés nem létező SKUt tartalmaz!",
-            ))?;
+  /// Create the UPLs not created by an unknown outcome call
+  /// Returns every created UPL ID
+  async fn reconcile_upls(&self, upls: Vec<UplNew>) -> ServiceResult<Vec<String>> {
+    let upl_ids = upls
+      .iter()
+      .map(|u| u.upl_id.clone())
+      .collect::<Vec<String>>();
+    let mut created = self
+      .load_upls(upl_ids.clone())
+      .await
+      .map_err(|e| {
+        ServiceError::internal_error(&format!("A UPL létrehozás eredménye ismeretlen! {}", e))
+      })?
+      .into_iter()
+      .map(|u| u.id)
+      .filter(|id| upl_ids.contains(id))
+      .collect::<Vec<String>>();
+    let missing = upls
+      .into_iter()
+      .filter(|u| !created.contains(&u.upl_id))
+      .collect::<Vec<UplNew>>();
+    if !missing.is_empty() {
+      let mut created_missing = self.create_upls(missing).await.map_err(|e| {
+        ServiceError::internal_error(&format!(
+          "UPL létrehozás sikertelen! {}",
+          ServiceError::from(e)
+        ))
+      })?;
+      created.append(&mut created_missing);
+    }
+    Ok(created)
+  }
 
-        // Try find related Price object
-        let price_obj = price_objects
-          .iter()
-          .find(|po| po.sku == sku_item.sku)
-          .ok_or(ServiceError::bad_request(&format!(
-            "A beszerzés alábbi SKUja nem rendelkezik eladási árral: #{}, {}",
-            sku_item.sku, sku_obj.display_name
-          )))?;
-
-        // Collect UPLs related to this SKU item
-        let mut u_candidates = procurement
-          .unpack()
-          .upl_candidates
-          .iter()
-          .filter(|upl_candidate| upl_candidate.sku == sku_item.sku)
-          .map(|uc| UplNew {
-            upl_id: uc.upl_id.clone(),
-            product_id: sku_obj.product_id,
-            sku: uc.sku,
-            best_before: match uc.best_before {
-              Some(bb) => bb.to_rfc3339().clone(),
-              None => "".to_string(),
-            },
-            stock_id: 1, // todo: refact this value to grab from ENV variable
-            procurement_id: procurement.unpack().id,
-            is_opened: uc.opened_sku,
-            created_by: procurement.created_by,
-            product_unit: sku_obj.unit.clone(),
-            piece: uc.upl_piece,
-            sku_divisible_amount: sku_obj.divisible_amount,
-            sku_divisible: sku_obj.can_divide,
-            sku_net_price: price_obj.price_net_retail,
-            sku_vat: price_obj.vat.clone(),
-            sku_gross_price: price_obj.price_gross_retail,
-            procurement_net_price_sku: sku_item.expected_net_price,
-          })
-          .collect::<Vec<UplNew>>();
-
-        // Check best_before if SKU is perishable
-        if sku_obj.perishable {
-          match u_candidates.iter().all(|uc| uc.best_before.len() > 0) {
-            true => (),
-            false => {
-              return Err(
-                ServiceError::bad_request(&format!(
-                  "Az alábbi SKU romlandó, viszont nem minden UPL-hez van lejárat rögzítve: {}",
-                  &sku_obj.display_name
-                ))
-                .into(),
-              )
-            }
+  /// Load SkuObjs for the given SKUs
+  async fn load_skus(&self, sku_id: Vec<u32>) -> ServiceResult<Vec<SkuObj>> {
+    self
+      .client_product
+      .call(|mut client| {
+        let sku_id = sku_id.clone();
+        async move {
+          let mut all_skus = client
+            .get_sku_bulk(GetSkuBulkRequest { sku_id })
+            .await?
+            .into_inner();
+
+          let mut sku_objects: Vec<SkuObj> = Vec::new();
+
+          while let Some(sku_obj) = all_skus.message().await? {
+            sku_objects.push(sku_obj);
           }
-        }
 
-        // Check if all UPL count is the required one
-        if u_candidates.iter().fold(0, |acc, uc| {
-          acc
-            + match uc.is_opened {
-              true => 1,
-              false => uc.piece,
-            }
-        }) != sku_item.ordered_amount
-        {
-          return Err(
-            ServiceError::bad_request(&format!(
-              "A beszerzés nem zárható le! Az alábbi SKU nem rendelkezik minden UPL-el: {}",
-              &sku_obj.display_name
-            ))
-            .into(),
-          );
+          Ok(sku_objects)
         }
+      })
+      .await
+  }
+
+  /// Render printable labels for UPL candidates
+  /// If no UPL ID is given, all the UPL candidates are rendered
+  async fn render_labels(&self, r: RenderLabelsRequest) -> ServiceResult<RenderLabelsResponse> {
+    let procurement = self.procurements.get(r.procurement_id).await?;
+
+    // Select UPL candidates to render
+    let upls = procurement
+      .upl_candidates
+      .iter()
+      .filter(|u| r.upl_ids.is_empty() || r.upl_ids.contains(&u.upl_id))
+      .collect::<Vec<&procurement::UplCandidate>>();
+
+    if let Some(missing) = r
+      .upl_ids
+      .iter()
+      .find(|id| !upls.iter().any(|u| &u.upl_id == *id))
+    {
+      return Err(ServiceError::bad_request(&format!(
+        "A megadott UPL azonosító nem szerepel a rendelésben: {}",
+        missing
+      )));
+    }
+
+    // Load SKU names and retail prices
+    let mut sku_ids = upls.iter().map(|u| u.sku).collect::<Vec<u32>>();
+    sku_ids.sort_unstable();
+    sku_ids.dedup();
+    let (sku_objects, price_objects) =
+      tokio::try_join!(self.load_skus(sku_ids.clone()), self.load_prices(sku_ids))?;
+
+    let template = template::load("PROCUREMENT_LABEL_TEMPLATE", label::DEFAULT_TEMPLATE)
+      .map_err(|e| ServiceError::internal_error(&e))?;
+
+    let labels = upls
+      .into_iter()
+      .map(|u| Label {
+        upl_id: u.upl_id.clone(),
+        payload: label::render(
+          &template,
+          u,
+          sku_objects.iter().find(|s| s.sku == u.sku),
+          price_objects.iter().find(|p| p.sku == u.sku),
+        ),
+      })
+      .collect::<Vec<Label>>();
+
+    Ok(RenderLabelsResponse {
+      payload: labels
+        .iter()
+        .map(|l| l.payload.as_str())
+        .collect::<Vec<&str>>()
+        .join(""),
+      labels,
+    })
+  }
+
+  /// Render purchase order document of a procurement
+  /// HTML only, see purchase_order::render
+  async fn render_purchase_order(
+    &self,
+    r: RenderPurchaseOrderRequest,
+  ) -> ServiceResult<RenderPurchaseOrderResponse> {
+    let procurement = self.procurements.get(r.procurement_id).await?;
+
+    // Load SKU names
+    let sku_objects = self
+      .load_skus(procurement.items.iter().map(|i| i.sku).collect())
+      .await?;
+
+    let document = purchase_order::render(&procurement, &sku_objects)
+      .map_err(|e| ServiceError::internal_error(&e))?;
+
+    Ok(RenderPurchaseOrderResponse {
+      content_type: "text/html".to_string(),
+      document,
+    })
+  }
+
+  /// Send purchase order document to the supplier by email
+  /// If no address is given, the supplier email address is used.
+  /// The send result is recorded on the procurement.
+  /// Disabled if the Source service is not configured
+  async fn send_purchase_order(
+    &self,
+    procurement_id: u32,
+    to: Option<String>,
+  ) -> ServiceResult<procurement::Procurement> {
+    let client_source = self.client_source.as_ref().ok_or_else(|| {
+      ServiceError::bad_request("A megrendelő küldése nincs beállítva! (SERVICE_ADDR_SOURCE)")
+    })?;
+
+    let procurement = self.procurements.get(procurement_id).await?;
+
+    // Only ordered procurement can be sent
+    if let procurement::Status::New = procurement.status {
+      return Err(ServiceError::bad_request(
+        "Csak megrendelt beszerzés megrendelője küldhető el!",
+      ));
+    }
 
-        // Add SKU related upl candidates into the result upl candidates
-        result_upl_candidates.append(&mut u_candidates);
-      }
-
-      // All UPL are fine, create request stream
-      let request = Request::new(stream::iter(result_upl_candidates));
-
-      // 4. Create UPLs
-      let created_upl_ids = self
-        .client_upl
-        .lock()
-        .await
-        .create_new_bulk(request)
-        .await
-        .map_err(|e| ServiceError::bad_request(&e.to_string()))?
-        .into_inner()
-        .upl_ids;
-
-      // Send email to sysadmin if not all UPLs are created!
-      if procurement.unpack().upl_candidates.len() != created_upl_ids.len() {
-        self
-          .client_email
-          .lock()
-          .await
-          .send_email(EmailRequest {
-            to: "peter.mezei@gardenova.hu".to_string(),
-            subject: "Proc hiba! Nem minden UPL jött létre!".to_string(),
-            body: format!(
-              "UPL létrehozás hiba! Nem minden UPL jött létre! Proc id: {}! {} helyett {}!",
-              procurement.id,
-              procurement.upl_candidates.len(),
-              created_upl_ids.len()
-            ),
+    // Get supplier email address
+    let to = match to {
+      Some(to) => to,
+      None => {
+        let source_id = procurement.source_id;
+        client_source
+          .call(|mut client| async move {
+            client
+              .get_by_id(proto::source::GetByIdRequest { source_id })
+              .await
           })
-          .await
-          .map_err(|e| ServiceError::bad_request(&e.to_string()))?;
+          .await?
+          .into_inner()
+          .email
       }
+    };
+
+    if to.is_empty() {
+      return Err(ServiceError::bad_request(
+        "A beszállítóhoz nincs email cím rögzítve!",
+      ));
+    }
+
+    // Render purchase order
+    let sku_objects = self
+      .load_skus(procurement.items.iter().map(|i| i.sku).collect())
+      .await?;
+    let document = purchase_order::render(&procurement, &sku_objects)
+      .map_err(|e| ServiceError::internal_error(&e))?;
+
+    // Send email and record the result
+    let request = EmailRequest {
+      to: to.clone(),
+      subject: match procurement.reference.len() {
+        x if x > 0 => format!(
+          "Megrendelés #{} ({})",
+          procurement.id, procurement.reference
+        ),
+        _ => format!("Megrendelés #{}", procurement.id),
+      },
+      body: document,
+    };
+    let send_result = self
+      .client_email
+      .call_once(|mut client| async move { client.send_email(request).await })
+      .await;
+
+    let res = self
+      .update(procurement_id, ChangeKind::Updated, |p| {
+        Ok(p.add_purchase_order_email(to, send_result.err().map(|e| e.to_string())))
+      })
+      .await?;
+
+    Ok(res)
+  }
+
+  /// Resend purchase order document to the supplier
+  async fn resend_purchase_order(
+    &self,
+    r: ResendPurchaseOrderRequest,
+  ) -> ServiceResult<ProcurementObject> {
+    let to = match r.to.len() {
+      x if x > 0 => Some(r.to),
+      _ => None,
+    };
+    let res = self.send_purchase_order(r.procurement_id, to).await?;
+    Ok(res.into())
+  }
+
+  /// Send alert through every configured notification channel
+  /// Errors are only logged, alerting never fails the caller
+  async fn send_alert(&self, event: notification::AlertEvent, values: &[(&str, String)]) {
+    self
+      .notifications
+      .send(event, values, event.recipients())
+      .await;
+  }
+
+  /// Load PriceObjects for the given SKUs
+  async fn load_prices(&self, skus: Vec<u32>) -> ServiceResult<Vec<PriceObject>> {
+    self
+      .client_pricing
+      .call(|mut client| {
+        let skus = skus.clone();
+        async move {
+          let mut all_prices = client
+            .get_price_bulk(GetPriceBulkRequest { skus })
+            .await?
+            .into_inner();
+
+          let mut price_objects: Vec<PriceObject> = Vec::new();
+
+          while let Some(price_obj) = all_prices.message().await? {
+            price_objects.push(price_obj);
+          }
+
+          Ok(price_objects)
+        }
+      })
+      .await
+  }
+
+  /// Get margin report of a procurement
+  /// Lists SKUs whose procurement price is above retail,
+  /// or whose margin is below the configured minimum
+  async fn get_margin_report(
+    &self,
+    r: GetMarginReportRequest,
+  ) -> ServiceResult<Vec<MarginReportObject>> {
+    let procurement = self.procurements.get(r.procurement_id).await?;
+
+    let price_objects = self
+      .load_prices(procurement.items.iter().map(|i| i.sku).collect())
+      .await?;
+
+    let res = margin::check(
+      &procurement,
+      &price_objects,
+      env_or("PROCUREMENT_MIN_MARGIN_PERCENT", 0.0),
+    )
+    .into_iter()
+    .map(|i| MarginReportObject {
+      sku: i.sku,
+      net_price: i.net_price,
+      retail_net_price: i.retail_net_price,
+      margin_percent: i.margin_percent,
+    })
+    .collect::<Vec<MarginReportObject>>();
+
+    Ok(res)
+  }
+
+  /// Create retail price update proposals for the given margin issues
+  /// Pending proposals of the same SKU are superseded,
+  /// except the ones being accepted right now
+  async fn create_price_proposals(
+    &self,
+    procurement_id: u32,
+    issues: Vec<margin::MarginIssue>,
+    prices: &[PriceObject],
+  ) -> ServiceResult<()> {
+    let target_margin = env_or("PROCUREMENT_TARGET_MARGIN_PERCENT", 25.0);
+    let mut proposals = self.price_proposals.lock().await;
+
+    // Calculate the next proposal ID
+    let first_id = proposals.iter().map(|p| p.unpack().id).max().unwrap_or(0) + 1;
+
+    for (i, issue) in issues.into_iter().enumerate() {
+      // Supersede pending proposals of the same SKU
+      let pending_ids = proposals
+        .iter()
+        .filter(|p| p.unpack().sku == issue.sku && p.unpack().is_pending())
+        .map(|p| p.unpack().id)
+        .filter(|id| !self.proposal_claims.is_claimed(*id))
+        .collect::<Vec<u32>>();
+      for pending_id in pending_ids {
+        proposals
+          .find_id_mut(&pending_id)?
+          .as_mut()
+          .unpack()
+          .supersede()
+          .map_err(|e| ServiceError::internal_error(&e))?;
+      }
+
+      let vat = prices
+        .iter()
+        .find(|p| p.sku == issue.sku)
+        .map(|p| p.vat.clone())
+        .unwrap_or_default();
+
+      proposals.insert(price_proposal::PriceProposal::new(
+        first_id + i as u32,
+        issue.sku,
+        procurement_id,
+        issue.net_price,
+        issue.retail_net_price,
+        price_proposal::proposed_retail_price(issue.net_price, target_margin),
+        vat,
+      ))?;
     }
 
     Ok(())
   }
 
+  /// Get retail price update proposals
+  async fn get_price_proposals(
+    &self,
+    r: GetPriceProposalsRequest,
+  ) -> ServiceResult<Vec<PriceProposalObject>> {
+    let res = self
+      .price_proposals
+      .lock()
+      .await
+      .iter()
+      .filter(|p| !r.only_pending || p.unpack().is_pending())
+      .map(|p| p.unpack().clone().into())
+      .collect::<Vec<PriceProposalObject>>();
+    Ok(res)
+  }
+
+  /// Try to accept price proposal
+  /// Pushes the new retail price to the pricing service
+  /// Proposal is claimed meanwhile, so the price is pushed only once
+  async fn accept_price_proposal(
+    &self,
+    r: PriceProposalDecisionRequest,
+  ) -> ServiceResult<PriceProposalObject> {
+    let claim = self
+      .proposal_claims
+      .claim(r.proposal_id)
+      .map_err(|e| ServiceError::bad_request(&e))?;
+
+    let proposal = self
+      .price_proposals
+      .lock()
+      .await
+      .find_id(&r.proposal_id)?
+      .unpack()
+      .clone();
+
+    if !proposal.is_pending() {
+      return Err(ServiceError::bad_request(
+        "Az árjavaslat már el lett bírálva!",
+      ));
+    }
+
+    // Manager can override the proposed price
+    let retail_net_price = match r.retail_net_price {
+      0 => proposal.proposed_retail_net_price,
+      x => x,
+    };
+
+    // Proposals of SKUs without price has no VAT,
+    // so it must be given by the manager
+    let vat = match r.vat.len() {
+      x if x > 0 => r.vat.clone(),
+      _ => proposal.vat.clone(),
+    };
+    if vat.is_empty() {
+      return Err(ServiceError::bad_request(
+        "Az árjavaslat elfogadásához meg kell adni az ÁFA kulcsot!",
+      ));
+    }
+
+    // Push new price to pricing service
+    let request = SetPriceRequest {
+      sku: proposal.sku,
+      price_net_retail: retail_net_price,
+      vat,
+      created_by: r.decided_by,
+    };
+    self
+      .client_pricing
+      .call_once(|mut client| async move { client.set_price(request).await })
+      .await?;
+
+    let res = self
+      .price_proposals
+      .lock()
+      .await
+      .find_id_mut(&r.proposal_id)?
+      .as_mut()
+      .unpack()
+      .accept(retail_net_price, r.decided_by)
+      .map_err(|e| ServiceError::bad_request(&e))?
+      .clone();
+    drop(claim);
+
+    // SKU has price now, remove it from the procurements awaiting pricing
+    let procurements = self.procurements.read().await;
+    let awaiting_ids = procurements
+      .iter()
+      .filter(|p| p.unpack().awaiting_pricing.contains(&res.sku))
+      .map(|p| p.unpack().id)
+      .collect::<Vec<u32>>();
+    drop(procurements);
+    for id in awaiting_ids {
+      self
+        .update(id, ChangeKind::Updated, |p| Ok(p.pricing_done(res.sku)))
+        .await?;
+    }
+
+    Ok(res.into())
+  }
+
+  /// Try to reject price proposal
+  async fn reject_price_proposal(
+    &self,
+    r: PriceProposalDecisionRequest,
+  ) -> ServiceResult<PriceProposalObject> {
+    let _claim = self
+      .proposal_claims
+      .claim(r.proposal_id)
+      .map_err(|e| ServiceError::bad_request(&e))?;
+
+    let res = self
+      .price_proposals
+      .lock()
+      .await
+      .find_id_mut(&r.proposal_id)?
+      .as_mut()
+      .unpack()
+      .reject(r.decided_by)
+      .map_err(|e| ServiceError::bad_request(&e))?
+      .clone();
+
+    Ok(res.into())
+  }
+
+  /// Try to close procurement
+  /// Works on a snapshot without holding any lock,
+  /// the caller must hold the claim of the procurement.
+  /// Returns the non blocking warnings and the SKUs awaiting pricing
+  async fn try_close(&self, id: u32) -> ServiceResult<(Vec<String>, Vec<u32>)> {
+    let mut warnings: Vec<String> = Vec::new();
+    let procurement = self.procurements.get(id).await?;
+
+    // 1. Check if status is Processing
+    match procurement.status {
+      procurement::Status::Processing => (),
+      _ => {
+        return Err(ServiceError::bad_request(
+          "A beszerzés nem zárható le! A státusz nem Feldolgozás alatt.",
+        ))
+      }
+    }
+
+    // Three-way match between ordered, received and invoiced values
+    let differences = procurement
+      .three_way_match(env_or("PROCUREMENT_INVOICE_TOLERANCE_PERCENT", 0))
+      .map_err(|e| ServiceError::bad_request(&format!("A beszerzés nem zárható le! {}", e)))?;
+
+    if !differences.is_empty() {
+      return Err(ServiceError::bad_request(&format!(
+        "A beszerzés nem zárható le! A rendelt, beérkezett és számlázott értékek eltérnek: {}",
+        differences
+          .iter()
+          .map(|d| format!(
+            "#{} (rendelt: {} db; beérkezett: {} db, {} Ft; számlázott: {} db, {} Ft)",
+            d.sku,
+            d.ordered_amount,
+            d.received_amount,
+            d.net_price,
+            d.invoiced_amount,
+            d.invoiced_net_price
+          ))
+          .collect::<Vec<String>>()
+          .join(", ")
+      )));
+    }
+
+    // 2. Check if all new UPL IDS are not already taken
+    let new_upl_ids = procurement
+      .upl_candidates
+      .iter()
+      .map(|u| u.upl_id.clone())
+      .collect::<Vec<String>>();
+
+    // Collect SKU IDs
+    let sku_id = procurement
+      .items
+      .iter()
+      .map(|i| i.sku)
+      .collect::<Vec<u32>>();
+
+    // Load existing UPLs, SKU objects to access SKU and product data,
+    // and PriceObjects to access SKU price data concurrently
+    let (all_upls, sku_objects, price_objects) = tokio::try_join!(
+      self.load_upls(new_upl_ids),
+      self.load_skus(sku_id.clone()),
+      self.load_prices(sku_id),
+    )?;
+
+    // If there is any found UPL with a new ID, then return error!
+    if all_upls.len() > 0 {
+      return Err(
+        ServiceError::bad_request(&format!(
+          "A beszerzés nem zárható le. Az alábbi UPL azonosítók már használatban vannak: {:?}",
+          all_upls.into_iter().map(|u| u.id).collect::<Vec<String>>(),
+        ))
+        .into(),
+      );
+    }
+
+    // Margin check against the retail prices
+    let min_margin = env_or("PROCUREMENT_MIN_MARGIN_PERCENT", 0.0);
+    let margin_issues = margin::check(&procurement, &price_objects, min_margin);
+
+    if !margin_issues.is_empty() {
+      let msg = format!(
+        "Az alábbi SKU-k beszerzési ára nem fér bele a minimális árrésbe ({}%): {}",
+        min_margin,
+        margin_issues
+          .iter()
+          .map(|i| format!(
+            "#{} (beszerzési ár: {} Ft, eladási ár: {} Ft, árrés: {:.1}%)",
+            i.sku, i.net_price, i.retail_net_price, i.margin_percent
+          ))
+          .collect::<Vec<String>>()
+          .join(", ")
+      );
+      match margin::MarginCheckMode::from_env() {
+        margin::MarginCheckMode::Block => {
+          return Err(ServiceError::bad_request(&format!(
+            "A beszerzés nem zárható le! {}",
+            msg
+          )))
+        }
+        margin::MarginCheckMode::Warn => warnings.push(msg),
+      }
+    }
+
+    // Collect SKUs whose new cost pushes the margin below target
+    // to create retail price update proposals after close
+    let proposal_issues = margin::check(
+      &procurement,
+      &price_objects,
+      env_or("PROCUREMENT_TARGET_MARGIN_PERCENT", 25.0),
+    );
+
+    // Create empty result vector
+    let mut result_upl_candidates: Vec<UplNew> = Vec::new();
+
+    // SKUs without retail price, closed with pending price
+    let missing_price_mode = price_proposal::MissingPriceMode::from_env();
+    let mut missing_price_skus: Vec<u32> = Vec::new();
+
+    for sku_item in procurement.items.iter() {
+      // Try find related SKU object
+      let sku_obj =
+        sku_objects
+          .iter()
+          .find(|so| so.sku == sku_item.sku)
+          .ok_or(ServiceError::bad_request(
+            "A beszerzés nem létező SKUt tartalmaz!",
+          ))?;
+
+      // Try find related Price object
+      // If missing, depending on the close mode fail,
+      // or create UPLs with pending (zero) price
+      let price_obj = match price_objects.iter().find(|po| po.sku == sku_item.sku) {
+        Some(price_obj) => Some(price_obj),
+        None => match missing_price_mode {
+          price_proposal::MissingPriceMode::Fail => {
+            return Err(ServiceError::bad_request(&format!(
+              "A beszerzés alábbi SKUja nem rendelkezik eladási árral: #{}, {}",
+              sku_item.sku, sku_obj.display_name
+            )))
+          }
+          price_proposal::MissingPriceMode::Pending => {
+            missing_price_skus.push(sku_item.sku);
+            None
+          }
+        },
+      };
+
+      // Collect UPLs related to this SKU item
+      let mut u_candidates = procurement
+        .upl_candidates
+        .iter()
+        .filter(|upl_candidate| upl_candidate.sku == sku_item.sku)
+        .map(|uc| UplNew {
+          upl_id: uc.upl_id.clone(),
+          product_id: sku_obj.product_id,
+          sku: uc.sku,
+          best_before: match uc.best_before {
+            Some(bb) => bb.to_rfc3339().clone(),
+            None => "".to_string(),
+          },
+          stock_id: procurement.upl_stock_id(uc),
+          procurement_id: procurement.id,
+          is_opened: uc.opened_sku,
+          created_by: procurement.created_by,
+          product_unit: sku_obj.unit.clone(),
+          piece: uc.upl_piece,
+          sku_divisible_amount: sku_obj.divisible_amount,
+          sku_divisible: sku_obj.can_divide,
+          sku_net_price: price_obj.map(|p| p.price_net_retail).unwrap_or(0),
+          sku_vat: price_obj.map(|p| p.vat.clone()).unwrap_or_default(),
+          sku_gross_price: price_obj.map(|p| p.price_gross_retail).unwrap_or(0),
+          procurement_net_price_sku: sku_item.get_net_price(),
+        })
+        .collect::<Vec<UplNew>>();
+
+      // Check if all UPL has destination stock
+      if let Some(uc) = u_candidates.iter().find(|uc| uc.stock_id == 0) {
+        return Err(ServiceError::bad_request(&format!(
+          "A beszerzés nem zárható le! Az alábbi UPL-hez nincs raktár megadva: {}",
+          uc.upl_id
+        )));
+      }
+
+      // Check best_before if SKU is perishable
+      if sku_obj.perishable {
+        match u_candidates.iter().all(|uc| uc.best_before.len() > 0) {
+          true => (),
+          false => {
+            return Err(
+              ServiceError::bad_request(&format!(
+                "Az alábbi SKU romlandó, viszont nem minden UPL-hez van lejárat rögzítve: {}",
+                &sku_obj.display_name
+              ))
+              .into(),
+            )
+          }
+        }
+      }
+
+      // Check if all UPL count is the required one
+      if u_candidates.iter().fold(0, |acc, uc| {
+        acc
+          + match uc.is_opened {
+            true => 1,
+            false => uc.piece,
+          }
+      }) != sku_item.ordered_amount
+      {
+        return Err(
+          ServiceError::bad_request(&format!(
+            "A beszerzés nem zárható le! Az alábbi SKU nem rendelkezik minden UPL-el: {}",
+            &sku_obj.display_name
+          ))
+          .into(),
+        );
+      }
+
+      // Add SKU related upl candidates into the result upl candidates
+      result_upl_candidates.append(&mut u_candidates);
+    }
+
+    // 4. Create UPLs
+    // If the outcome is unknown (timeout or lost connection),
+    // the created UPLs are reconciled and only the missing ones are created
+    let created_upl_ids = match self.create_upls(result_upl_candidates.clone()).await {
+      Ok(upl_ids) => upl_ids,
+      // Nothing was sent, the service is unavailable
+      Err(downstream::OnceError::NotSent(e)) => return Err(e),
+      Err(downstream::OnceError::UnknownOutcome(e)) => {
+        eprintln!(
+          "UPL creation outcome is unknown for procurement {}, reconciling: {}",
+          procurement.id, e
+        );
+        self.reconcile_upls(result_upl_candidates).await?
+      }
+      Err(downstream::OnceError::Failed(e)) => {
+        return Err(ServiceError::internal_error(&format!(
+          "UPL létrehozás sikertelen! {}",
+          e
+        )))
+      }
+    };
+
+    // Send alert if not all UPLs are created!
+    if procurement.upl_candidates.len() != created_upl_ids.len() {
+      self
+        .send_alert(
+          notification::AlertEvent::UplCreationMismatch,
+          &[
+            ("procurement_id", procurement.id.to_string()),
+            ("expected", procurement.upl_candidates.len().to_string()),
+            ("created", created_upl_ids.len().to_string()),
+          ],
+        )
+        .await;
+    }
+
+    // SKUs without retail price get a pricing task (price proposal)
+    // and the procurement is flagged as awaiting pricing
+    let mut proposal_issues = proposal_issues;
+    if !missing_price_skus.is_empty() {
+      warnings.push(format!(
+        "Az alábbi SKU-k eladási ár nélkül kerültek bevételezésre, árazásra várnak: {:?}",
+        missing_price_skus
+      ));
+      for item in procurement
+        .items
+        .iter()
+        .filter(|i| missing_price_skus.contains(&i.sku))
+      {
+        proposal_issues.push(margin::MarginIssue {
+          sku: item.sku,
+          net_price: item.get_net_price(),
+          retail_net_price: 0,
+          margin_percent: margin::margin_percent(item.get_net_price(), 0),
+        });
+      }
+    }
+
+    // Create retail price update proposals
+    // UPLs are already created, so the close must go on
+    if let Err(e) = self
+      .create_price_proposals(procurement.id, proposal_issues, &price_objects)
+      .await
+    {
+      warnings.push(format!("Az árjavaslatok létrehozása sikertelen! {}", e));
+    }
+
+    Ok((warnings, missing_price_skus))
+  }
+
   /// Try to set new Status to the procurement
   async fn set_status(&self, r: SetStatusRequest) -> ServiceResult<ProcurementObject> {
     // Set requested new status
@@ -602,26 +1750,248 @@ impl ProcurementService {
       proto::procurement::Status::New => procurement::Status::New,
     };
 
-    match new_status {
+    let (warnings, locked) = match new_status {
       // If new status is closed, try to close it
-      procurement::Status::Closed => self.try_close(r.procurement_id).await?,
-      _ => (),
-    }
+      // and send alert if it fails for other than validation.
+      // Procurement is claimed while closing, so it cannot change
+      // meanwhile, but no lock is held during the downstream calls
+      procurement::Status::Closed => {
+        let claim = self.procurements.claim(r.procurement_id).await?;
+        match self.try_close(r.procurement_id).await {
+          Ok((warnings, awaiting_pricing)) => {
+            let mut locked = self.procurements.lock_claimed(claim).await?;
+            if !awaiting_pricing.is_empty() {
+              locked.procurement.set_awaiting_pricing(awaiting_pricing);
+            }
+            (warnings, locked)
+          }
+          // Validation errors are returned to the user only,
+          // alert is sent on infrastructure and UPL creation failures
+          Err(e @ ServiceError::BadRequest(_))
+          | Err(e @ ServiceError::NotFound(_))
+          | Err(e @ ServiceError::AlreadyExists(_)) => return Err(e),
+          Err(e) => {
+            self
+              .send_alert(
+                notification::AlertEvent::CloseFailure,
+                &[
+                  ("procurement_id", r.procurement_id.to_string()),
+                  ("error", e.to_string()),
+                ],
+              )
+              .await;
+            return Err(e);
+          }
+        }
+      }
+      _ => (Vec::new(), self.procurements.lock(r.procurement_id).await?),
+    };
 
     // Try to set new status
     let res = self
-      .procurements
+      .apply(locked, ChangeKind::StatusChanged, |p| {
+        p.set_status(new_status, r.created_by)
+      })
+      .await?;
+
+    // Index purchase prices of the closed procurement
+    if let procurement::Status::Closed = res.status {
+      self.price_history.lock().await.add_procurement(&res);
+    }
+
+    // Send purchase order to the supplier if requested
+    // Sending error does not revert the status change
+    let mut warnings = warnings;
+    let res = match (&res.status, r.send_purchase_order) {
+      (procurement::Status::Ordered, true) => {
+        match self.send_purchase_order(r.procurement_id, None).await {
+          Ok(res) => {
+            if let Some(email) = res.purchase_order_emails.last() {
+              if !email.success {
+                warnings.push(format!(
+                  "A megrendelő küldése sikertelen! {}: {}",
+                  email.to, email.error
+                ));
+              }
+            }
+            res
+          }
+          Err(e) => {
+            warnings.push(format!("A megrendelő küldése sikertelen! {}", e));
+            res
+          }
+        }
+      }
+      _ => res,
+    };
+
+    // Return procurement as ProcurementObject with warnings
+    let mut res: ProcurementObject = res.into();
+    res.warnings = warnings;
+    Ok(res)
+  }
+
+  /// Get availability of the downstream services
+  /// and the features not working meanwhile
+  async fn get_health(&self, _r: GetHealthRequest) -> ServiceResult<HealthResponse> {
+    let mut dependencies = vec![
+      self.client_upl.status(),
+      self.client_product.status(),
+      self.client_pricing.status(),
+      self.client_email.status(),
+    ];
+    if let Some(client_source) = &self.client_source {
+      dependencies.push(client_source.status());
+    }
+    let mut degraded_features = dependencies
+      .iter()
+      .filter(|d| !d.available)
+      .flat_map(|d| d.features.iter().map(|f| f.to_string()))
+      .collect::<Vec<String>>();
+    degraded_features.sort();
+    degraded_features.dedup();
+    Ok(HealthResponse {
+      degraded: !degraded_features.is_empty(),
+      dependencies: dependencies
+        .into_iter()
+        .map(|d| d.into())
+        .collect::<Vec<DependencyHealth>>(),
+      degraded_features,
+    })
+  }
+
+  /// Try to register webhook subscription
+  async fn register_webhook(&self, r: RegisterWebhookRequest) -> ServiceResult<WebhookObject> {
+    if !r.url.starts_with("http://") && !r.url.starts_with("https://") {
+      return Err(ServiceError::bad_request(
+        "A webhook URL-nek http:// vagy https:// előtaggal kell kezdődnie!",
+      ));
+    }
+    if r.secret.len() < 16 {
+      return Err(ServiceError::bad_request(
+        "Az aláíró kulcs legalább 16 karakter hosszú legyen!",
+      ));
+    }
+
+    let mut subscriptions = self.webhooks.subscriptions.lock().await;
+    let subscription = webhook::WebhookSubscription {
+      id: subscriptions
+        .iter()
+        .map(|s| s.unpack().id)
+        .max()
+        .unwrap_or(0)
+        + 1,
+      url: r.url,
+      event_types: r
+        .event_types
+        .into_iter()
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect(),
+      secret: r.secret,
+      created_at: Utc::now(),
+      created_by: r.created_by,
+    };
+    subscriptions.insert(subscription.clone())?;
+
+    Ok(subscription.into())
+  }
+
+  /// Get webhook subscriptions
+  async fn get_webhooks(&self, _r: GetWebhooksRequest) -> ServiceResult<Vec<WebhookObject>> {
+    let res = self
+      .webhooks
+      .subscriptions
       .lock()
       .await
-      .find_id_mut(&r.procurement_id)?
-      .as_mut()
-      .unpack()
-      .set_status(new_status, r.created_by)
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+      .iter()
+      .map(|s| s.unpack().clone().into())
+      .collect::<Vec<WebhookObject>>();
+    Ok(res)
+  }
 
-    // Return procurement as ProcurementObject
-    Ok(res.into())
+  /// Try to delete webhook subscription
+  /// with its pending and dead deliveries
+  async fn delete_webhook(&self, r: DeleteWebhookRequest) -> ServiceResult<()> {
+    self
+      .webhooks
+      .subscriptions
+      .lock()
+      .await
+      .remove_pack(&r.webhook_id)?;
+
+    let mut deliveries = self.webhooks.deliveries.lock().await;
+    let delivery_ids = deliveries
+      .iter()
+      .filter(|d| d.unpack().subscription_id == r.webhook_id)
+      .map(|d| d.unpack().id.clone())
+      .collect::<Vec<String>>();
+    for id in delivery_ids {
+      deliveries.remove_pack(&id)?;
+    }
+
+    Ok(())
+  }
+
+  /// Get dead webhook deliveries
+  /// webhook_id 0 means any subscription
+  async fn get_dead_letters(
+    &self,
+    r: GetDeadLettersRequest,
+  ) -> ServiceResult<Vec<WebhookDeliveryObject>> {
+    let res = self
+      .webhooks
+      .deliveries
+      .lock()
+      .await
+      .iter()
+      .map(|d| d.unpack())
+      .filter(|d| d.dead && (r.webhook_id == 0 || d.subscription_id == r.webhook_id))
+      .map(|d| d.clone().into())
+      .collect::<Vec<WebhookDeliveryObject>>();
+    Ok(res)
+  }
+
+  /// Try to replay dead webhook deliveries
+  /// If no delivery ID is given, every dead delivery
+  /// of the webhook (or of any webhook if 0) is replayed
+  async fn replay_dead_letters(
+    &self,
+    r: ReplayDeadLettersRequest,
+  ) -> ServiceResult<ReplayDeadLettersResponse> {
+    let mut deliveries = self.webhooks.deliveries.lock().await;
+    let ids = match r.delivery_ids.is_empty() {
+      true => deliveries
+        .iter()
+        .map(|d| d.unpack())
+        .filter(|d| d.dead && (r.webhook_id == 0 || d.subscription_id == r.webhook_id))
+        .map(|d| d.id.clone())
+        .collect::<Vec<String>>(),
+      false => r.delivery_ids,
+    };
+    for id in &ids {
+      deliveries
+        .find_id_mut(id)?
+        .as_mut()
+        .unpack()
+        .replay()
+        .map_err(|e| ServiceError::bad_request(&e))?;
+    }
+    Ok(ReplayDeadLettersResponse {
+      replayed_count: ids.len() as u32,
+    })
+  }
+
+  /// Get last run and outcome of the background jobs
+  async fn get_job_status(&self, _r: GetJobStatusRequest) -> ServiceResult<Vec<JobStatusObject>> {
+    let res = self
+      .jobs
+      .lock()
+      .await
+      .values()
+      .map(|j| j.clone().into())
+      .collect::<Vec<JobStatusObject>>();
+    Ok(res)
   }
 }
 
@@ -629,36 +1999,115 @@ impl ProcurementService {
 impl Procurement for ProcurementService {
   async fn create_new(
     &self,
-    request: Request<CreateNewRequest>,
+    request: Request<CreateNewRequest>,
+  ) -> Result<Response<ProcurementObject>, Status> {
+    let res = self.create_new(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_by_id(
+    &self,
+    request: Request<GetByIdRequest>,
+  ) -> Result<Response<ProcurementObject>, Status> {
+    let res = self.get_by_id(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_all(&self, _request: Request<()>) -> Result<Response<ProcurementIds>, Status> {
+    let procurement_ids = self.get_all().await?;
+    Ok(Response::new(ProcurementIds { procurement_ids }))
+  }
+
+  type GetInfoBulkStream = ReceiverStream<Result<ProcurementInfoObject, Status>>;
+
+  async fn get_info_bulk(
+    &self,
+    request: Request<GetInfoBulkRequest>,
+  ) -> Result<Response<Self::GetInfoBulkStream>, Status> {
+    // Create channel for stream response
+    let (mut tx, rx) = tokio::sync::mpsc::channel(100);
+
+    // Get resources as Vec<SourceObject>
+    let res = self.get_info_bulk(request.into_inner()).await?;
+
+    // Send the result items through the channel
+    tokio::spawn(async move {
+      for ots in res.into_iter() {
+        tx.send(Ok(ots)).await.unwrap();
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn set_delivery_date(
+    &self,
+    request: Request<SetDeliveryDateRequest>,
+  ) -> Result<Response<ProcurementObject>, Status> {
+    let res = self.set_delivery(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn set_stock(
+    &self,
+    request: Request<SetStockRequest>,
+  ) -> Result<Response<ProcurementObject>, Status> {
+    let res = self.set_stock(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_all_by_stock(
+    &self,
+    request: Request<GetAllByStockRequest>,
+  ) -> Result<Response<ProcurementIds>, Status> {
+    let procurement_ids = self.get_all_by_stock(request.into_inner()).await?;
+    Ok(Response::new(ProcurementIds { procurement_ids }))
+  }
+
+  async fn set_reference(
+    &self,
+    request: Request<SetReferenceRequest>,
+  ) -> Result<Response<ProcurementObject>, Status> {
+    let res = self.set_reference(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn set_invoice(
+    &self,
+    request: Request<SetInvoiceRequest>,
   ) -> Result<Response<ProcurementObject>, Status> {
-    let res = self.create_new(request.into_inner()).await?;
+    let res = self.set_invoice(request.into_inner()).await?;
     Ok(Response::new(res))
   }
 
-  async fn get_by_id(
+  async fn set_invoice_item(
     &self,
-    request: Request<GetByIdRequest>,
+    request: Request<SetInvoiceItemRequest>,
   ) -> Result<Response<ProcurementObject>, Status> {
-    let res = self.get_by_id(request.into_inner()).await?;
+    let res = self.set_invoice_item(request.into_inner()).await?;
     Ok(Response::new(res))
   }
 
-  async fn get_all(&self, _request: Request<()>) -> Result<Response<ProcurementIds>, Status> {
-    let procurement_ids = self.get_all().await?;
-    Ok(Response::new(ProcurementIds { procurement_ids }))
+  async fn add_sku(
+    &self,
+    request: Request<AddSkuRequest>,
+  ) -> Result<Response<ProcurementObject>, Status> {
+    let res = self.add_sku(request.into_inner()).await?;
+    Ok(Response::new(res))
   }
 
-  type GetInfoBulkStream = ReceiverStream<Result<ProcurementInfoObject, Status>>;
+  type GetPriceHistoryStream = ReceiverStream<Result<PriceHistoryObject, Status>>;
 
-  async fn get_info_bulk(
+  async fn get_price_history(
     &self,
-    request: Request<GetInfoBulkRequest>,
-  ) -> Result<Response<Self::GetInfoBulkStream>, Status> {
+    request: Request<GetPriceHistoryRequest>,
+  ) -> Result<Response<Self::GetPriceHistoryStream>, Status> {
     // Create channel for stream response
-    let (mut tx, rx) = tokio::sync::mpsc::channel(100);
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
 
-    // Get resources as Vec<SourceObject>
-    let res = self.get_info_bulk(request.into_inner()).await?;
+    // Get price history as Vec<PriceHistoryObject>
+    let res = self.get_price_history(request.into_inner()).await?;
 
     // Send the result items through the channel
     tokio::spawn(async move {
@@ -671,43 +2120,82 @@ impl Procurement for ProcurementService {
     Ok(Response::new(ReceiverStream::new(rx)))
   }
 
-  async fn set_delivery_date(
+  async fn remove_sku(
     &self,
-    request: Request<SetDeliveryDateRequest>,
+    request: Request<RemoveSkuRequest>,
   ) -> Result<Response<ProcurementObject>, Status> {
-    let res = self.set_delivery(request.into_inner()).await?;
+    let res = self.remove_sku(request.into_inner()).await?;
     Ok(Response::new(res))
   }
 
-  async fn set_reference(
+  async fn set_sku_piece(
     &self,
-    request: Request<SetReferenceRequest>,
+    request: Request<SetSkuPieceRequest>,
   ) -> Result<Response<ProcurementObject>, Status> {
-    let res = self.set_reference(request.into_inner()).await?;
+    let res = self.set_sku_piece(request.into_inner()).await?;
     Ok(Response::new(res))
   }
 
-  async fn add_sku(
+  async fn set_sku_actual_price(
     &self,
-    request: Request<AddSkuRequest>,
+    request: Request<SetSkuActualPriceRequest>,
   ) -> Result<Response<ProcurementObject>, Status> {
-    let res = self.add_sku(request.into_inner()).await?.clone();
+    let res = self.set_sku_actual_price(request.into_inner()).await?;
     Ok(Response::new(res))
   }
 
-  async fn remove_sku(
+  type GetPriceVarianceStream = ReceiverStream<Result<PriceVarianceObject, Status>>;
+
+  async fn get_price_variance(
     &self,
-    request: Request<RemoveSkuRequest>,
-  ) -> Result<Response<ProcurementObject>, Status> {
-    let res = self.remove_sku(request.into_inner()).await?;
+    request: Request<GetPriceVarianceRequest>,
+  ) -> Result<Response<Self::GetPriceVarianceStream>, Status> {
+    // Create channel for stream response
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+    // Get variance report as Vec<PriceVarianceObject>
+    let res = self.get_price_variance(request.into_inner()).await?;
+
+    // Send the result items through the channel
+    tokio::spawn(async move {
+      for ots in res.into_iter() {
+        tx.send(Ok(ots)).await.unwrap();
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn reserve_upl_ids(
+    &self,
+    request: Request<ReserveUplIdsRequest>,
+  ) -> Result<Response<ReserveUplIdsResponse>, Status> {
+    let upl_ids = self.reserve_upl_ids(request.into_inner()).await?;
+    Ok(Response::new(ReserveUplIdsResponse { upl_ids }))
+  }
+
+  async fn render_purchase_order(
+    &self,
+    request: Request<RenderPurchaseOrderRequest>,
+  ) -> Result<Response<RenderPurchaseOrderResponse>, Status> {
+    let res = self.render_purchase_order(request.into_inner()).await?;
     Ok(Response::new(res))
   }
 
-  async fn set_sku_piece(
+  async fn resend_purchase_order(
     &self,
-    request: Request<SetSkuPieceRequest>,
+    request: Request<ResendPurchaseOrderRequest>,
   ) -> Result<Response<ProcurementObject>, Status> {
-    let res = self.set_sku_piece(request.into_inner()).await?;
+    let res = self.resend_purchase_order(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn render_labels(
+    &self,
+    request: Request<RenderLabelsRequest>,
+  ) -> Result<Response<RenderLabelsResponse>, Status> {
+    let res = self.render_labels(request.into_inner()).await?;
     Ok(Response::new(res))
   }
 
@@ -719,6 +2207,77 @@ impl Procurement for ProcurementService {
     Ok(Response::new(res))
   }
 
+  async fn add_upl_bulk(
+    &self,
+    request: Request<tonic::Streaming<AddUplBulkRequest>>,
+  ) -> Result<Response<AddUplBulkResponse>, Status> {
+    // Collect the incoming stream
+    let mut stream = request.into_inner();
+    let mut items: Vec<AddUplBulkRequest> = Vec::new();
+    while let Some(item) = stream.message().await? {
+      items.push(item);
+    }
+    let res = self.add_upl_bulk(items).await?;
+    Ok(Response::new(res))
+  }
+
+  type ReceivingSessionStream = ReceiverStream<Result<ScanAck, Status>>;
+
+  async fn receiving_session(
+    &self,
+    request: Request<tonic::Streaming<ScanEvent>>,
+  ) -> Result<Response<Self::ReceivingSessionStream>, Status> {
+    // Create channel for stream response
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+    let (procurements, changes, client_product) = (
+      self.procurements.clone(),
+      self.changes.clone(),
+      self.client_product.clone(),
+    );
+    let mut stream = request.into_inner();
+
+    // Process scan events one by one, and send back
+    // the acknowledgements through the channel
+    tokio::spawn(async move {
+      let mut barcodes = std::collections::HashMap::new();
+      loop {
+        match stream.message().await {
+          Ok(Some(event)) => {
+            let ack = receiving::process_scan(
+              &procurements,
+              &changes,
+              &client_product,
+              &mut barcodes,
+              event,
+            )
+            .await;
+            if tx.send(Ok(ack)).await.is_err() {
+              break;
+            }
+          }
+          // Scanner closed the session
+          Ok(None) => break,
+          Err(e) => {
+            let _ = tx.send(Err(e)).await;
+            break;
+          }
+        }
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn sync_scan_batch(
+    &self,
+    request: Request<SyncScanBatchRequest>,
+  ) -> Result<Response<SyncScanBatchResponse>, Status> {
+    let res = self.sync_scan_batch(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
   async fn update_upl(
     &self,
     request: Request<UpdateUplRequest>,
@@ -755,32 +2314,429 @@ impl Procurement for ProcurementService {
     let res = self.set_status(request.into_inner()).await?;
     Ok(Response::new(res))
   }
+
+  type GetPriceProposalsStream = ReceiverStream<Result<PriceProposalObject, Status>>;
+
+  async fn get_price_proposals(
+    &self,
+    request: Request<GetPriceProposalsRequest>,
+  ) -> Result<Response<Self::GetPriceProposalsStream>, Status> {
+    // Create channel for stream response
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+    // Get proposals as Vec<PriceProposalObject>
+    let res = self.get_price_proposals(request.into_inner()).await?;
+
+    // Send the result items through the channel
+    tokio::spawn(async move {
+      for ots in res.into_iter() {
+        tx.send(Ok(ots)).await.unwrap();
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn accept_price_proposal(
+    &self,
+    request: Request<PriceProposalDecisionRequest>,
+  ) -> Result<Response<PriceProposalObject>, Status> {
+    let res = self.accept_price_proposal(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn reject_price_proposal(
+    &self,
+    request: Request<PriceProposalDecisionRequest>,
+  ) -> Result<Response<PriceProposalObject>, Status> {
+    let res = self.reject_price_proposal(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  type GetMarginReportStream = ReceiverStream<Result<MarginReportObject, Status>>;
+
+  async fn get_margin_report(
+    &self,
+    request: Request<GetMarginReportRequest>,
+  ) -> Result<Response<Self::GetMarginReportStream>, Status> {
+    // Create channel for stream response
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+    // Get margin report as Vec<MarginReportObject>
+    let res = self.get_margin_report(request.into_inner()).await?;
+
+    // Send the result items through the channel
+    tokio::spawn(async move {
+      for ots in res.into_iter() {
+        tx.send(Ok(ots)).await.unwrap();
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn get_health(
+    &self,
+    request: Request<GetHealthRequest>,
+  ) -> Result<Response<HealthResponse>, Status> {
+    let res = self.get_health(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn register_webhook(
+    &self,
+    request: Request<RegisterWebhookRequest>,
+  ) -> Result<Response<WebhookObject>, Status> {
+    let res = self.register_webhook(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  type GetWebhooksStream = ReceiverStream<Result<WebhookObject, Status>>;
+
+  async fn get_webhooks(
+    &self,
+    request: Request<GetWebhooksRequest>,
+  ) -> Result<Response<Self::GetWebhooksStream>, Status> {
+    // Create channel for stream response
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+    // Get webhooks as Vec<WebhookObject>
+    let res = self.get_webhooks(request.into_inner()).await?;
+
+    // Send the result items through the channel
+    tokio::spawn(async move {
+      for ots in res.into_iter() {
+        tx.send(Ok(ots)).await.unwrap();
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn delete_webhook(
+    &self,
+    request: Request<DeleteWebhookRequest>,
+  ) -> Result<Response<()>, Status> {
+    self.delete_webhook(request.into_inner()).await?;
+    Ok(Response::new(()))
+  }
+
+  type GetDeadLettersStream = ReceiverStream<Result<WebhookDeliveryObject, Status>>;
+
+  async fn get_dead_letters(
+    &self,
+    request: Request<GetDeadLettersRequest>,
+  ) -> Result<Response<Self::GetDeadLettersStream>, Status> {
+    // Create channel for stream response
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+    // Get dead deliveries as Vec<WebhookDeliveryObject>
+    let res = self.get_dead_letters(request.into_inner()).await?;
+
+    // Send the result items through the channel
+    tokio::spawn(async move {
+      for ots in res.into_iter() {
+        tx.send(Ok(ots)).await.unwrap();
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn replay_dead_letters(
+    &self,
+    request: Request<ReplayDeadLettersRequest>,
+  ) -> Result<Response<ReplayDeadLettersResponse>, Status> {
+    let res = self.replay_dead_letters(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  type WatchStream = ReceiverStream<Result<ChangeEvent, Status>>;
+
+  async fn watch(
+    &self,
+    request: Request<WatchRequest>,
+  ) -> Result<Response<Self::WatchStream>, Status> {
+    // Create channel for stream response
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+    let filter = request.into_inner();
+
+    // Get the missed events and subscribe to the new ones
+    let (backlog, mut events) = self
+      .changes
+      .subscribe(filter.from_seq, &filter.epoch)
+      .await
+      .map_err(Status::out_of_range)?;
+
+    // Send the matching events through the channel
+    // until the watcher disconnects
+    tokio::spawn(async move {
+      for event in backlog {
+        if change_feed::matches(&filter, &event) && tx.send(Ok(event)).await.is_err() {
+          return;
+        }
+      }
+      loop {
+        match events.recv().await {
+          Ok(event) => {
+            if change_feed::matches(&filter, &event) && tx.send(Ok(event)).await.is_err() {
+              return;
+            }
+          }
+          // Watcher is too slow, it must resume from its last sequence
+          Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {
+            let _ = tx
+              .send(Err(Status::data_loss(
+                "A figyelő lemaradt! Kérem folytassa az utolsó sorszámtól.",
+              )))
+              .await;
+            return;
+          }
+          Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+        }
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  type GetJobStatusStream = ReceiverStream<Result<JobStatusObject, Status>>;
+
+  async fn get_job_status(
+    &self,
+    request: Request<GetJobStatusRequest>,
+  ) -> Result<Response<Self::GetJobStatusStream>, Status> {
+    // Create channel for stream response
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+    // Get job status as Vec<JobStatusObject>
+    let res = self.get_job_status(request.into_inner()).await?;
+
+    // Send the result items through the channel
+    tokio::spawn(async move {
+      for ots in res.into_iter() {
+        tx.send(Ok(ots)).await.unwrap();
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
 }
 
 #[tokio::main]
 async fn main() -> prelude::ServiceResult<()> {
+  // Fail on start instead of the first reservation
+  upl_id::length_from_env().expect("Invalid UPL ID configuration");
+
   let db: VecPack<procurement::Procurement> =
     VecPack::load_or_init(PathBuf::from("data/procurement"))
       .expect("Error while loading procurement db");
 
-  let client_upl = UplClient::connect(service_address("SERVICE_ADDR_UPL"))
-    .await
-    .expect("Could not connect to UPL service");
+  let db_price_proposal: VecPack<price_proposal::PriceProposal> =
+    VecPack::load_or_init(PathBuf::from("data/price_proposal"))
+      .expect("Error while loading price proposal db");
+
+  // Downstream services are connected lazily, so the service
+  // starts and serves reads while any of them is down.
+  // Features listed are not working while the service is unavailable.
+  let client_upl = Downstream::new(
+    "UPL",
+    UplClient::new(downstream::connect_lazy("SERVICE_ADDR_UPL")),
+    &["close", "reserve_upl_ids", "upl_reconciliation"],
+  );
+
+  let client_product = Downstream::new(
+    "Product",
+    ProductClient::new(downstream::connect_lazy("SERVICE_ADDR_PRODUCT")),
+    &[
+      "close",
+      "render_labels",
+      "render_purchase_order",
+      "send_purchase_order",
+      "barcode_scan",
+    ],
+  );
+
+  let client_pricing = Downstream::new(
+    "Pricing",
+    PricingClient::new(downstream::connect_lazy("SERVICE_ADDR_PRICING")),
+    &[
+      "close",
+      "render_labels",
+      "margin_report",
+      "accept_price_proposal",
+    ],
+  );
+
+  let client_email = Downstream::new(
+    "Email",
+    EmailClient::new(downstream::connect_lazy("SERVICE_ADDR_EMAIL")),
+    &["send_purchase_order", "email_notifications"],
+  );
+
+  // Optional, purchase orders are not sent without it
+  let client_source = match env::var("SERVICE_ADDR_SOURCE") {
+    Ok(_) => Some(Downstream::new(
+      "Source",
+      SourceClient::new(downstream::connect_lazy("SERVICE_ADDR_SOURCE")),
+      &["send_purchase_order"],
+    )),
+    Err(_) => {
+      eprintln!("SERVICE_ADDR_SOURCE is not set, purchase order sending is disabled");
+      None
+    }
+  };
+
+  let db_outbox: VecPack<notifier::OutboxMessage> =
+    VecPack::load_or_init(PathBuf::from("data/notification_outbox"))
+      .expect("Error while loading notification outbox db");
+
+  let notifications = Arc::new(notifier::NotificationCenter::new(
+    notifier::channels_from_env(client_email.clone()),
+    db_outbox,
+  ));
+
+  // Retry undelivered notifications in the background
+  let notifications_relay = notifications.clone();
+  tokio::task::spawn(async move {
+    let interval = env_or("PROCUREMENT_NOTIFY_RETRY_INTERVAL_SECS", 30);
+    loop {
+      tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+      notifications_relay.deliver_due().await;
+    }
+  });
 
-  let client_product = ProductClient::connect(service_address("SERVICE_ADDR_PRODUCT"))
-    .await
-    .expect("Could not connect to PRODUCT service");
+  let db_integration_outbox: VecPack<procurement::IntegrationEvent> =
+    VecPack::load_or_init(PathBuf::from("data/integration_outbox"))
+      .expect("Error while loading integration outbox db");
 
-  let client_pricing = PricingClient::connect(service_address("SERVICE_ADDR_PRICING"))
-    .await
-    .expect("Could not connect to PRICING service");
+  let webhooks = Arc::new(webhook::Webhooks::new(
+    VecPack::load_or_init(PathBuf::from("data/webhook_subscription"))
+      .expect("Error while loading webhook subscription db"),
+    VecPack::load_or_init(PathBuf::from("data/webhook_delivery"))
+      .expect("Error while loading webhook delivery db"),
+  ));
 
-  let client_email = EmailClient::connect(service_address("SERVICE_ADDR_EMAIL"))
-    .await
-    .expect("Could not connect to email service");
+  // Deliver webhooks in the background
+  let webhooks_relay = webhooks.clone();
+  tokio::task::spawn(async move {
+    let interval = env_or("PROCUREMENT_WEBHOOK_RETRY_INTERVAL_SECS", 5);
+    loop {
+      webhooks_relay.deliver_due().await;
+      tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+    }
+  });
 
-  let procurement_service =
-    ProcurementService::new(db, client_upl, client_product, client_pricing, client_email);
+  let db_archive: VecPack<procurement::Procurement> =
+    VecPack::load_or_init(PathBuf::from("data/procurement_archive"))
+      .expect("Error while loading procurement archive db");
+
+  let mut scheduler = scheduler::Scheduler::new();
+  let client_upl_jobs = client_upl.clone();
+
+  let procurement_service = ProcurementService::new(
+    db,
+    db_archive,
+    db_price_proposal,
+    db_integration_outbox,
+    webhooks.clone(),
+    client_upl,
+    client_product,
+    client_pricing,
+    client_email,
+    client_source,
+    notifications.clone(),
+    scheduler.registry(),
+  );
+
+  // Register background jobs
+  // Schedules are cron-like expressions in UTC, "off" disables a job
+  let (procurements, archive, price_history) = (
+    procurement_service.procurements.clone(),
+    procurement_service.archive.clone(),
+    procurement_service.price_history.clone(),
+  );
+
+  let (p, n) = (procurements.clone(), notifications.clone());
+  scheduler
+    .add(
+      "overdue_check",
+      "PROCUREMENT_JOB_OVERDUE_CHECK",
+      "0 7 * * *",
+      move || {
+        let (p, n) = (p.clone(), n.clone());
+        async move {
+          let count = overdue::check(&p, &n).await;
+          Ok(format!("{} overdue procurement", count))
+        }
+      },
+    )
+    .await;
+
+  let (p, a) = (procurements.clone(), archive.clone());
+  scheduler
+    .add(
+      "archival",
+      "PROCUREMENT_JOB_ARCHIVAL",
+      "0 2 * * *",
+      move || {
+        let (p, a) = (p.clone(), a.clone());
+        async move { jobs::archive(&p, &a, env_or("PROCUREMENT_ARCHIVE_AFTER_DAYS", 365)).await }
+      },
+    )
+    .await;
+
+  let (p, a, ph) = (procurements.clone(), archive.clone(), price_history.clone());
+  scheduler
+    .add(
+      "price_history_rebuild",
+      "PROCUREMENT_JOB_PRICE_HISTORY_REBUILD",
+      "30 2 * * *",
+      move || {
+        let (p, a, ph) = (p.clone(), a.clone(), ph.clone());
+        async move { jobs::rebuild_price_history(&p, &a, &ph).await }
+      },
+    )
+    .await;
+
+  let (p, n) = (procurements.clone(), notifications.clone());
+  scheduler
+    .add(
+      "upl_reconciliation",
+      "PROCUREMENT_JOB_UPL_RECONCILIATION",
+      "0 3 * * *",
+      move || {
+        let (p, n, c) = (p.clone(), n.clone(), client_upl_jobs.clone());
+        async move {
+          jobs::reconcile_upls(&p, c, &n, env_or("PROCUREMENT_UPL_RECONCILE_DAYS", 30)).await
+        }
+      },
+    )
+    .await;
+
+  scheduler.start();
+
+  // Deliver integration events to the configured endpoint
+  // and to the webhook subscriptions
+  let mut sinks: Vec<Box<dyn integration::EventSink>> =
+    vec![Box::new(webhook::WebhookSink::new(webhooks))];
+  if let Some(sink) = integration::sink_from_env() {
+    sinks.push(sink);
+  }
+  let (p, o) = (
+    procurements.clone(),
+    procurement_service.integration_outbox.clone(),
+  );
+  tokio::task::spawn(async move {
+    integration::relay(sinks, p, o).await;
+  });
 
   let addr = env::var("SERVICE_ADDR_PROCUREMENT")
     .unwrap_or("[::1]:50063".into())
@@ -807,3 +2763,135 @@ async fn main() -> prelude::ServiceResult<()> {
 
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  #[tokio::test]
+  async fn test_close() {
+    let service = mock::service(Duration::ZERO, vec![mock::procurement_to_close(1, 2, 3)]).await;
+    let (warnings, awaiting_pricing) = service.try_close(1).await.unwrap();
+    assert!(warnings.is_empty());
+    assert!(awaiting_pricing.is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_close_without_invoice() {
+    let mut procurement = mock::procurement_to_close(1, 2, 3);
+    procurement.invoice = None;
+    let service = mock::service(Duration::ZERO, vec![procurement]).await;
+    match service.try_close(1).await {
+      Err(ServiceError::BadRequest(msg)) => assert!(msg.contains("Nincs számla rögzítve!")),
+      _ => panic!("Close without invoice must fail"),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_close_without_price() {
+    std::env::set_var("PROCUREMENT_MISSING_PRICE_MODE", "pending");
+    let mut p = mock::procurement_to_close(1, 1, 1);
+    p.items.push(procurement::ProcurementItem::new(
+      mock::UNPRICED_SKU,
+      1,
+      1000,
+    ));
+    p.upl_candidates.push(procurement::UplCandidate {
+      upl_id: "unpriced".to_string(),
+      sku: mock::UNPRICED_SKU,
+      upl_piece: 1,
+      ..procurement::UplCandidate::default()
+    });
+    if let Some(invoice) = p.invoice.as_mut() {
+      invoice.items.push(procurement::InvoiceItem {
+        sku: mock::UNPRICED_SKU,
+        invoiced_amount: 1,
+        invoiced_net_price: 1000,
+      });
+    }
+    let service = mock::service(Duration::ZERO, vec![p]).await;
+
+    let res = service
+      .set_status(SetStatusRequest {
+        procurement_id: 1,
+        status: proto::procurement::Status::Closed as i32,
+        created_by: 1,
+        send_purchase_order: false,
+      })
+      .await
+      .unwrap();
+    assert!(res.warnings.iter().any(|w| w.contains("árazásra várnak")));
+    let closed = service.procurements.get(1).await.unwrap();
+    assert_eq!(closed.awaiting_pricing, vec![mock::UNPRICED_SKU]);
+
+    // Pricing task is created for the SKU without price only
+    let proposals = service
+      .get_price_proposals(GetPriceProposalsRequest { only_pending: true })
+      .await
+      .unwrap();
+    assert_eq!(proposals.len(), 1);
+    assert_eq!(proposals[0].sku, mock::UNPRICED_SKU);
+    assert_eq!(proposals[0].current_retail_net_price, 0);
+    assert!(proposals[0].vat.is_empty());
+
+    // Accepting it clears the awaiting pricing flag
+    service
+      .accept_price_proposal(PriceProposalDecisionRequest {
+        proposal_id: proposals[0].id,
+        retail_net_price: 0,
+        decided_by: 1,
+        vat: "27".to_string(),
+      })
+      .await
+      .unwrap();
+    let priced = service.procurements.get(1).await.unwrap();
+    assert!(!priced.is_awaiting_pricing());
+  }
+
+  #[tokio::test]
+  async fn test_send_purchase_order_without_source() {
+    let mut ordered = procurement::Procurement::new(1, 1, 5, 1);
+    ordered.status = procurement::Status::Ordered;
+    let mut service = mock::service(Duration::ZERO, vec![ordered]).await;
+    service.client_source = None;
+
+    match service
+      .send_purchase_order(1, Some("beszallito@example.com".to_string()))
+      .await
+    {
+      Err(ServiceError::BadRequest(msg)) => assert!(msg.contains("SERVICE_ADDR_SOURCE")),
+      _ => panic!("Purchase order must not be sent without Source service"),
+    }
+    let health = service.get_health(GetHealthRequest {}).await.unwrap();
+    assert!(health.dependencies.iter().all(|d| d.name != "Source"));
+  }
+
+  #[tokio::test]
+  async fn test_get_all_includes_archive() {
+    let mut archived = procurement::Procurement::new(2, 1, 5, 1);
+    archived.status = procurement::Status::Closed;
+    let service = mock::service(
+      Duration::ZERO,
+      vec![procurement::Procurement::new(1, 1, 5, 1)],
+    )
+    .await;
+    service.archive.lock().await.insert(archived).unwrap();
+    service
+      .procurements
+      .write()
+      .await
+      .insert(procurement::Procurement::new(3, 1, 6, 1))
+      .unwrap();
+
+    let mut all = service.get_all().await.unwrap();
+    all.sort_unstable();
+    assert_eq!(all, vec![1, 2, 3]);
+    let mut by_stock = service
+      .get_all_by_stock(GetAllByStockRequest { stock_id: 5 })
+      .await
+      .unwrap();
+    by_stock.sort_unstable();
+    assert_eq!(by_stock, vec![1, 2]);
+  }
+}
@@ -0,0 +1,133 @@
+use crate::procurement::Procurement;
+use gzlib::proto::pricing::PriceObject;
+
+/// What to do if a SKU does not fit the minimum margin
+pub enum MarginCheckMode {
+  // Close is refused
+  Block,
+  // Close is allowed, issues are returned as warnings
+  Warn,
+}
+
+impl MarginCheckMode {
+  /// Load mode from PROCUREMENT_MARGIN_CHECK env variable
+  /// "block" or "warn", warn is the default
+  pub fn from_env() -> Self {
+    match std::env::var("PROCUREMENT_MARGIN_CHECK") {
+      Ok(mode) if mode.to_lowercase() == "block" => MarginCheckMode::Block,
+      _ => MarginCheckMode::Warn,
+    }
+  }
+}
+
+/// SKU whose procurement price does not fit its retail price
+#[derive(Debug, Clone)]
+pub struct MarginIssue {
+  pub sku: u32,
+  pub net_price: u32,
+  pub retail_net_price: u32,
+  pub margin_percent: f64,
+}
+
+/// Calculate margin percent of the retail net price
+/// Negative if the procurement price is above retail
+pub fn margin_percent(net_price: u32, retail_net_price: u32) -> f64 {
+  match retail_net_price {
+    // No retail price, there is no margin at all
+    0 => -100.0,
+    retail => (retail as f64 - net_price as f64) / retail as f64 * 100.0,
+  }
+}
+
+/// Collect SKUs whose procurement net price is above retail,
+/// or whose margin is below the given minimum.
+/// SKUs without price object are skipped.
+pub fn check(
+  procurement: &Procurement,
+  prices: &[PriceObject],
+  min_margin_percent: f64,
+) -> Vec<MarginIssue> {
+  procurement
+    .items
+    .iter()
+    .filter_map(|item| {
+      let price = prices.iter().find(|p| p.sku == item.sku)?;
+      let net_price = item.get_net_price();
+      let margin = margin_percent(net_price, price.price_net_retail);
+      match net_price > price.price_net_retail || margin < min_margin_percent {
+        true => Some(MarginIssue {
+          sku: item.sku,
+          net_price,
+          retail_net_price: price.price_net_retail,
+          margin_percent: margin,
+        }),
+        false => None,
+      }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn price(sku: u32, price_net_retail: u32) -> PriceObject {
+    PriceObject {
+      sku,
+      price_net_retail,
+      ..PriceObject::default()
+    }
+  }
+
+  fn issue_skus(procurement: &Procurement, prices: &[PriceObject]) -> Vec<u32> {
+    check(procurement, prices, 10.0)
+      .iter()
+      .map(|i| i.sku)
+      .collect()
+  }
+
+  #[test]
+  fn test_margin_percent() {
+    assert_eq!(margin_percent(7500, 10000), 25.0);
+    assert_eq!(margin_percent(12000, 10000), -20.0);
+    assert_eq!(margin_percent(0, 10000), 100.0);
+    assert_eq!(margin_percent(5000, 0), -100.0);
+  }
+
+  #[test]
+  fn test_check_threshold() {
+    let mut p = Procurement::new(1, 1, 1, 1);
+    // Above, at and below the 10% minimum
+    p.sku_add(1, 1, 7500).unwrap();
+    p.sku_add(2, 1, 9000).unwrap();
+    p.sku_add(3, 1, 9500).unwrap();
+    // Above retail
+    p.sku_add(4, 1, 11000).unwrap();
+    // No price object
+    p.sku_add(5, 1, 9900).unwrap();
+    let prices = (1..=4).map(|sku| price(sku, 10000)).collect::<Vec<_>>();
+
+    assert_eq!(issue_skus(&p, &prices), vec![3, 4]);
+    let issue = &check(&p, &prices, 10.0)[0];
+    assert_eq!(issue.net_price, 9500);
+    assert_eq!(issue.retail_net_price, 10000);
+    assert_eq!(issue.margin_percent, 5.0);
+  }
+
+  #[test]
+  fn test_check_zero_price() {
+    let mut p = Procurement::new(1, 1, 1, 1);
+    // Free item with retail price
+    p.sku_add(1, 1, 0).unwrap();
+    // No retail price
+    p.sku_add(2, 1, 5000).unwrap();
+    // Neither
+    p.sku_add(3, 1, 0).unwrap();
+    let prices = vec![price(1, 10000), price(2, 0), price(3, 0)];
+
+    assert_eq!(issue_skus(&p, &prices), vec![2, 3]);
+    assert!(check(&p, &prices, 10.0)
+      .iter()
+      .all(|i| i.margin_percent == -100.0));
+  }
+}
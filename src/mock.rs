@@ -0,0 +1,223 @@
+//! In-process mock servers of the downstream services
+//! for the service level tests and benchmarks
+
+use crate::*;
+use gzlib::proto::pricing::{pricing_server, GetPriceBulkRequest, SetPriceRequest};
+use gzlib::proto::product::{product_server, GetSkuByBarcodeRequest};
+use gzlib::proto::upl::{upl_server, BulkRequest, CreateNewBulkResponse};
+use std::time::Duration;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::Endpoint;
+
+/// No UPL exists yet, every new one is created
+struct UplMock {
+  latency: Duration,
+}
+
+#[tonic::async_trait]
+impl upl_server::Upl for UplMock {
+  type GetBulkStream = ReceiverStream<Result<UplObj, Status>>;
+
+  async fn get_bulk(
+    &self,
+    _request: Request<BulkRequest>,
+  ) -> Result<Response<Self::GetBulkStream>, Status> {
+    tokio::time::sleep(self.latency).await;
+    let (_, rx) = tokio::sync::mpsc::channel(1);
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn create_new_bulk(
+    &self,
+    request: Request<tonic::Streaming<UplNew>>,
+  ) -> Result<Response<CreateNewBulkResponse>, Status> {
+    tokio::time::sleep(self.latency).await;
+    let mut upls = request.into_inner();
+    let mut upl_ids = Vec::new();
+    while let Some(upl) = upls.message().await? {
+      upl_ids.push(upl.upl_id);
+    }
+    Ok(Response::new(CreateNewBulkResponse { upl_ids }))
+  }
+}
+
+/// Every SKU exists
+struct ProductMock {
+  latency: Duration,
+}
+
+#[tonic::async_trait]
+impl product_server::Product for ProductMock {
+  type GetSkuBulkStream = ReceiverStream<Result<SkuObj, Status>>;
+
+  async fn get_sku_bulk(
+    &self,
+    request: Request<GetSkuBulkRequest>,
+  ) -> Result<Response<Self::GetSkuBulkStream>, Status> {
+    tokio::time::sleep(self.latency).await;
+    let skus = request.into_inner().sku_id;
+    let (tx, rx) = tokio::sync::mpsc::channel(skus.len().max(1));
+    for sku in skus {
+      let _ = tx
+        .send(Ok(SkuObj {
+          sku,
+          product_id: sku,
+          display_name: format!("SKU {}", sku),
+          unit: "db".to_string(),
+          divisible_amount: 1.0,
+          can_divide: false,
+          perishable: false,
+        }))
+        .await;
+    }
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn get_sku_by_barcode(
+    &self,
+    _request: Request<GetSkuByBarcodeRequest>,
+  ) -> Result<Response<SkuObj>, Status> {
+    Err(Status::unimplemented("Not used by the benchmark"))
+  }
+}
+
+/// SKUs from this one have no retail price
+pub const UNPRICED_SKU: u32 = 1000;
+
+/// Every SKU below UNPRICED_SKU has a retail price
+/// well above the procurement price
+struct PricingMock {
+  latency: Duration,
+}
+
+#[tonic::async_trait]
+impl pricing_server::Pricing for PricingMock {
+  type GetPriceBulkStream = ReceiverStream<Result<PriceObject, Status>>;
+
+  async fn get_price_bulk(
+    &self,
+    request: Request<GetPriceBulkRequest>,
+  ) -> Result<Response<Self::GetPriceBulkStream>, Status> {
+    tokio::time::sleep(self.latency).await;
+    let skus = request.into_inner().skus;
+    let (tx, rx) = tokio::sync::mpsc::channel(skus.len().max(1));
+    for sku in skus.into_iter().filter(|sku| *sku < UNPRICED_SKU) {
+      let _ = tx
+        .send(Ok(PriceObject {
+          sku,
+          price_net_retail: 10000,
+          vat: "27".to_string(),
+          price_gross_retail: 12700,
+        }))
+        .await;
+    }
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn set_price(
+    &self,
+    request: Request<SetPriceRequest>,
+  ) -> Result<Response<PriceObject>, Status> {
+    tokio::time::sleep(self.latency).await;
+    let r = request.into_inner();
+    Ok(Response::new(PriceObject {
+      sku: r.sku,
+      price_net_retail: r.price_net_retail,
+      vat: r.vat,
+      price_gross_retail: 0,
+    }))
+  }
+}
+
+/// Start the mock servers on a random local port
+/// Returns the channel to them
+pub async fn start_mocks(latency: Duration) -> Channel {
+  let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let addr = listener.local_addr().unwrap();
+  tokio::spawn(
+    Server::builder()
+      .add_service(upl_server::UplServer::new(UplMock { latency }))
+      .add_service(product_server::ProductServer::new(ProductMock { latency }))
+      .add_service(pricing_server::PricingServer::new(PricingMock { latency }))
+      .serve_with_incoming(TcpListenerStream::new(listener)),
+  );
+  Endpoint::from_shared(format!("http://{}", addr))
+    .unwrap()
+    .connect_lazy()
+    .unwrap()
+}
+
+pub fn temp_pack<T: VecPackMember>(name: &str) -> VecPack<T> {
+  VecPack::load_or_init(std::env::temp_dir().join(format!(
+    "procurement_bench_{}_{}",
+    name,
+    rand::random::<u32>()
+  )))
+  .unwrap()
+}
+
+/// Procurement ready to close with the given SKUs,
+/// each received in the given number of UPLs and invoiced
+pub fn procurement_to_close(id: u32, skus: u32, upls_per_sku: u32) -> procurement::Procurement {
+  let mut p = procurement::Procurement::new(id, 1, 1, 1);
+  for sku in 1..=skus {
+    p.items
+      .push(procurement::ProcurementItem::new(sku, upls_per_sku, 1000));
+    for i in 0..upls_per_sku {
+      p.upl_candidates.push(procurement::UplCandidate {
+        upl_id: format!("{}-{}-{}", id, sku, i),
+        sku,
+        upl_piece: 1,
+        ..procurement::UplCandidate::default()
+      });
+    }
+  }
+  p.invoice = Some(procurement::Invoice {
+    invoice_number: format!("INV-{}", id),
+    invoice_date: None,
+    due_date: None,
+    items: (1..=skus)
+      .map(|sku| procurement::InvoiceItem {
+        sku,
+        invoiced_amount: upls_per_sku,
+        invoiced_net_price: 1000,
+      })
+      .collect(),
+  });
+  p.status = procurement::Status::Processing;
+  p
+}
+
+/// Service connected to the mock servers
+/// with the given procurements
+pub async fn service(
+  latency: Duration,
+  procurements: Vec<procurement::Procurement>,
+) -> ProcurementService {
+  let channel = start_mocks(latency).await;
+  let mut db = temp_pack("procurement");
+  for p in procurements {
+    db.insert(p).unwrap();
+  }
+  ProcurementService::new(
+    db,
+    temp_pack("procurement_archive"),
+    temp_pack("price_proposal"),
+    temp_pack("integration_outbox"),
+    Arc::new(webhook::Webhooks::new(
+      temp_pack("webhook_subscription"),
+      temp_pack("webhook_delivery"),
+    )),
+    Downstream::new("UPL", UplClient::new(channel.clone()), &[]),
+    Downstream::new("Product", ProductClient::new(channel.clone()), &[]),
+    Downstream::new("Pricing", PricingClient::new(channel.clone()), &[]),
+    // Not served, not called while closing
+    Downstream::new("Email", EmailClient::new(channel.clone()), &[]),
+    Some(Downstream::new("Source", SourceClient::new(channel), &[])),
+    Arc::new(notifier::NotificationCenter::new(
+      Vec::new(),
+      temp_pack("notification_outbox"),
+    )),
+    scheduler::JobRegistry::default(),
+  )
+}
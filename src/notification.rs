@@ -0,0 +1,91 @@
+use crate::template;
+
+/// Procurement events that send alerts
+#[derive(Debug, Clone, Copy)]
+pub enum AlertEvent {
+  // Not all UPLs were created by the UPL service at close
+  UplCreationMismatch,
+  // Ordered procurement passed its estimated delivery date
+  OverdueDelivery,
+  // Procurement could not be closed because of
+  // an infrastructure or UPL creation failure
+  CloseFailure,
+}
+
+impl AlertEvent {
+  /// Event name used in notification payloads
+  pub fn name(&self) -> &'static str {
+    match self {
+      AlertEvent::UplCreationMismatch => "upl_creation_mismatch",
+      AlertEvent::OverdueDelivery => "overdue_delivery",
+      AlertEvent::CloseFailure => "close_failure",
+    }
+  }
+
+  /// Env key of the comma separated recipient list
+  fn recipients_env_key(&self) -> &'static str {
+    match self {
+      AlertEvent::UplCreationMismatch => "PROCUREMENT_ALERT_UPL_MISMATCH",
+      AlertEvent::OverdueDelivery => "PROCUREMENT_ALERT_OVERDUE_DELIVERY",
+      AlertEvent::CloseFailure => "PROCUREMENT_ALERT_CLOSE_FAILURE",
+    }
+  }
+
+  /// Env key of the template file path
+  fn template_env_key(&self) -> &'static str {
+    match self {
+      AlertEvent::UplCreationMismatch => "PROCUREMENT_ALERT_UPL_MISMATCH_TEMPLATE",
+      AlertEvent::OverdueDelivery => "PROCUREMENT_ALERT_OVERDUE_DELIVERY_TEMPLATE",
+      AlertEvent::CloseFailure => "PROCUREMENT_ALERT_CLOSE_FAILURE_TEMPLATE",
+    }
+  }
+
+  /// Default template; first line is the subject,
+  /// the rest is the body
+  fn default_template(&self) -> &'static str {
+    match self {
+      AlertEvent::UplCreationMismatch => "Proc hiba! Nem minden UPL jött létre!
+UPL létrehozás hiba! Nem minden UPL jött létre! Proc id: {{procurement_id}}! {{expected}} helyett {{created}}!
+",
+      AlertEvent::OverdueDelivery => "Késésben lévő beszerzések
+Az alábbi megrendelt beszerzések várható érkezési dátuma már elmúlt:
+{{procurements}}
+",
+      AlertEvent::CloseFailure => "Proc hiba! A beszerzés nem zárható le!
+A beszerzés lezárása sikertelen! Proc id: {{procurement_id}}!
+{{error}}
+",
+    }
+  }
+
+  /// Get configured recipients
+  /// Empty if alert is not configured
+  pub fn recipients(&self) -> Vec<String> {
+    match std::env::var(self.recipients_env_key()) {
+      Ok(list) => list
+        .split(',')
+        .map(|r| r.trim().to_string())
+        .filter(|r| !r.is_empty())
+        .collect(),
+      Err(_) => Vec::new(),
+    }
+  }
+}
+
+/// Rendered alert message
+#[derive(Debug, Clone)]
+pub struct Alert {
+  pub subject: String,
+  pub body: String,
+}
+
+/// Build alert message from the event template
+pub fn build(event: AlertEvent, values: &[(&str, String)]) -> Result<Alert, String> {
+  let template = template::load(event.template_env_key(), event.default_template())?;
+  let rendered = template::render(&template, values);
+  let mut lines = rendered.splitn(2, '\n');
+  Ok(Alert {
+    subject: lines.next().unwrap_or_default().trim().to_string(),
+    body: lines.next().unwrap_or_default().to_string(),
+  })
+}
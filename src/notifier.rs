@@ -0,0 +1,450 @@
+use crate::downstream::Downstream;
+use crate::notification::{Alert, AlertEvent};
+use chrono::prelude::*;
+use gzlib::proto::email::{email_client::EmailClient, EmailRequest};
+use packman::{VecPack, VecPackMember};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+use tonic::transport::Channel;
+
+/// Notification channel
+#[tonic::async_trait]
+pub trait Notifier: Send + Sync {
+  /// Unique channel name, used in configuration and in the outbox
+  fn name(&self) -> &'static str;
+  /// If true, every recipient gets its own outbox message,
+  /// so a failed recipient does not resend to the others
+  fn per_recipient(&self) -> bool {
+    false
+  }
+  /// Try to deliver the alert to the recipients
+  async fn notify(&self, message: &OutboxMessage) -> Result<(), String>;
+}
+
+/// Email channel through the email service
+pub struct EmailNotifier {
+  client: Downstream<EmailClient<Channel>>,
+}
+
+impl EmailNotifier {
+  pub fn new(client: Downstream<EmailClient<Channel>>) -> Self {
+    Self { client }
+  }
+}
+
+#[tonic::async_trait]
+impl Notifier for EmailNotifier {
+  fn name(&self) -> &'static str {
+    "email"
+  }
+
+  fn per_recipient(&self) -> bool {
+    true
+  }
+
+  async fn notify(&self, message: &OutboxMessage) -> Result<(), String> {
+    for to in &message.recipients {
+      let request = EmailRequest {
+        to: to.clone(),
+        subject: message.subject.clone(),
+        body: message.body.clone(),
+      };
+      self
+        .client
+        .call_once(|mut client| async move { client.send_email(request).await })
+        .await
+        .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+  }
+}
+
+/// Generic HTTP webhook channel
+/// Posts the alert as JSON to the configured URL
+pub struct WebhookNotifier {
+  client: reqwest::Client,
+  url: String,
+}
+
+impl WebhookNotifier {
+  pub fn new(url: String) -> Self {
+    Self {
+      client: crate::prelude::http_client(),
+      url,
+    }
+  }
+}
+
+#[tonic::async_trait]
+impl Notifier for WebhookNotifier {
+  fn name(&self) -> &'static str {
+    "webhook"
+  }
+
+  async fn notify(&self, message: &OutboxMessage) -> Result<(), String> {
+    self
+      .client
+      .post(&self.url)
+      .json(&serde_json::json!({
+        "event": message.event,
+        "subject": message.subject,
+        "body": message.body,
+        "recipients": message.recipients,
+        "created_at": message.created_at.to_rfc3339(),
+      }))
+      .send()
+      .await
+      .and_then(|res| res.error_for_status())
+      .map_err(|e| e.to_string())?;
+    Ok(())
+  }
+}
+
+/// Local append-only file sink, mainly for testing
+/// Every alert is appended as a JSON line
+pub struct FileNotifier {
+  path: PathBuf,
+}
+
+impl FileNotifier {
+  pub fn new(path: PathBuf) -> Self {
+    Self { path }
+  }
+}
+
+#[tonic::async_trait]
+impl Notifier for FileNotifier {
+  fn name(&self) -> &'static str {
+    "file"
+  }
+
+  async fn notify(&self, message: &OutboxMessage) -> Result<(), String> {
+    let line = serde_json::json!({
+      "event": message.event,
+      "subject": message.subject,
+      "body": message.body,
+      "recipients": message.recipients,
+      "created_at": message.created_at.to_rfc3339(),
+    })
+    .to_string();
+    let mut file = tokio::fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&self.path)
+      .await
+      .map_err(|e| e.to_string())?;
+    file
+      .write_all(format!("{}\n", line).as_bytes())
+      .await
+      .map_err(|e| e.to_string())
+  }
+}
+
+/// Alert waiting for delivery on a channel
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OutboxMessage {
+  pub id: u32,
+  pub channel: String,
+  pub event: String,
+  pub subject: String,
+  pub body: String,
+  pub recipients: Vec<String>,
+  pub attempts: u32,
+  pub last_error: String,
+  pub next_attempt_at: DateTime<Utc>,
+  // Max attempts reached, no more retry
+  pub failed: bool,
+  pub created_at: DateTime<Utc>,
+}
+
+impl OutboxMessage {
+  /// Register a failed delivery attempt
+  /// Next attempt is scheduled with exponential backoff
+  pub fn attempt_failed(&mut self, error: String, max_attempts: u32) -> &Self {
+    self.attempts += 1;
+    self.last_error = error;
+    // 30s, 60s, 120s .. max 1 hour
+    let delay = (30 * 2i64.pow(self.attempts.min(7) - 1)).min(3600);
+    self.next_attempt_at = Utc::now() + chrono::Duration::seconds(delay);
+    self.failed = self.attempts >= max_attempts;
+    self
+  }
+}
+
+impl VecPackMember for OutboxMessage {
+  type Out = u32;
+
+  fn get_id(&self) -> &Self::Out {
+    &self.id
+  }
+}
+
+impl Default for OutboxMessage {
+  fn default() -> Self {
+    Self {
+      id: 0,
+      channel: "".into(),
+      event: "".into(),
+      subject: "".into(),
+      body: "".into(),
+      recipients: Vec::new(),
+      attempts: 0,
+      last_error: "".into(),
+      next_attempt_at: Utc::now(),
+      failed: false,
+      created_at: Utc::now(),
+    }
+  }
+}
+
+/// Fans out alerts to every configured channel
+/// through a persistent outbox
+pub struct NotificationCenter {
+  channels: Vec<Box<dyn Notifier>>,
+  outbox: Mutex<VecPack<OutboxMessage>>,
+  // Messages being delivered, guarded by the outbox lock
+  in_flight: Mutex<HashSet<u32>>,
+  max_attempts: u32,
+}
+
+impl NotificationCenter {
+  pub fn new(channels: Vec<Box<dyn Notifier>>, outbox: VecPack<OutboxMessage>) -> Self {
+    Self {
+      channels,
+      outbox: Mutex::new(outbox),
+      in_flight: Mutex::new(HashSet::new()),
+      max_attempts: crate::prelude::env_or("PROCUREMENT_NOTIFY_MAX_ATTEMPTS", 10),
+    }
+  }
+
+  /// Build alert from the event template and queue it
+  /// Errors are only logged, alerting never fails the caller
+  pub async fn send(&self, event: AlertEvent, values: &[(&str, String)], recipients: Vec<String>) {
+    let alert = match crate::notification::build(event, values) {
+      Ok(alert) => alert,
+      Err(e) => {
+        eprintln!("Error while building alert {:?}: {}", event, e);
+        return;
+      }
+    };
+    if let Err(e) = self.enqueue(event, alert, recipients).await {
+      eprintln!("Error while queueing alert {:?}: {}", event, e);
+    }
+  }
+
+  /// Store alert in the outbox for every channel,
+  /// or for every recipient of per recipient channels,
+  /// then try to deliver it right away
+  pub async fn enqueue(
+    &self,
+    event: AlertEvent,
+    alert: Alert,
+    recipients: Vec<String>,
+  ) -> Result<(), String> {
+    {
+      let mut outbox = self.outbox.lock().await;
+      let mut next_id = outbox.iter().map(|m| m.unpack().id).max().unwrap_or(0) + 1;
+      for channel in &self.channels {
+        let recipient_groups = match channel.per_recipient() {
+          true => recipients.iter().map(|r| vec![r.clone()]).collect(),
+          false => vec![recipients.clone()],
+        };
+        for recipients in recipient_groups {
+          outbox
+            .insert(OutboxMessage {
+              id: next_id,
+              channel: channel.name().to_string(),
+              event: event.name().to_string(),
+              subject: alert.subject.clone(),
+              body: alert.body.clone(),
+              recipients,
+              ..OutboxMessage::default()
+            })
+            .map_err(|e| e.to_string())?;
+          next_id += 1;
+        }
+      }
+    }
+    self.deliver_due().await;
+    Ok(())
+  }
+
+  /// Try to deliver every due message in the outbox
+  /// Delivered messages are removed, failed ones are rescheduled.
+  /// Messages are marked in-flight, so concurrent calls
+  /// do not deliver the same message twice
+  pub async fn deliver_due(&self) {
+    let now = Utc::now();
+    // Do not hold the outbox lock while delivering
+    let due = {
+      let outbox = self.outbox.lock().await;
+      let mut in_flight = self.in_flight.lock().await;
+      outbox
+        .iter()
+        .map(|m| m.unpack())
+        .filter(|m| !m.failed && m.next_attempt_at <= now)
+        .filter(|m| in_flight.insert(m.id))
+        .cloned()
+        .collect::<Vec<OutboxMessage>>()
+    };
+
+    for message in due {
+      let result = match self.channels.iter().find(|c| c.name() == message.channel) {
+        Some(channel) => channel.notify(&message).await,
+        None => Err(format!("Channel is not configured: {}", message.channel)),
+      };
+      let mut outbox = self.outbox.lock().await;
+      let res = match result {
+        Ok(_) => outbox.remove_pack(&message.id).map(|_| ()),
+        Err(e) => {
+          eprintln!(
+            "Error while sending {} alert through {}: {}",
+            message.event, message.channel, e
+          );
+          outbox.find_id_mut(&message.id).map(|m| {
+            m.as_mut().unpack().attempt_failed(e, self.max_attempts);
+          })
+        }
+      };
+      if let Err(e) = res {
+        eprintln!("Error while updating notification outbox: {}", e);
+      }
+      self.in_flight.lock().await.remove(&message.id);
+    }
+  }
+}
+
+/// Create the configured notification channels
+/// PROCUREMENT_NOTIFY_CHANNELS is a comma separated list of
+/// email, webhook and file; email is the default
+pub fn channels_from_env(client_email: Downstream<EmailClient<Channel>>) -> Vec<Box<dyn Notifier>> {
+  let names = std::env::var("PROCUREMENT_NOTIFY_CHANNELS").unwrap_or("email".into());
+  let mut res: Vec<Box<dyn Notifier>> = Vec::new();
+  for name in names.split(',').map(|n| n.trim()) {
+    match name {
+      "email" => res.push(Box::new(EmailNotifier::new(client_email.clone()))),
+      "webhook" => match std::env::var("PROCUREMENT_NOTIFY_WEBHOOK_URL") {
+        Ok(url) => res.push(Box::new(WebhookNotifier::new(url))),
+        Err(_) => eprintln!("PROCUREMENT_NOTIFY_WEBHOOK_URL is not set, webhook channel skipped"),
+      },
+      "file" => res.push(Box::new(FileNotifier::new(PathBuf::from(
+        std::env::var("PROCUREMENT_NOTIFY_FILE").unwrap_or("data/notifications.log".into()),
+      )))),
+      "" => (),
+      _ => eprintln!("Unknown notification channel: {}", name),
+    }
+  }
+  res
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicU32, Ordering};
+  use std::sync::Arc;
+
+  struct CountingNotifier {
+    sent: Arc<AtomicU32>,
+    per_recipient: bool,
+  }
+
+  #[tonic::async_trait]
+  impl Notifier for CountingNotifier {
+    fn name(&self) -> &'static str {
+      "counting"
+    }
+
+    fn per_recipient(&self) -> bool {
+      self.per_recipient
+    }
+
+    async fn notify(&self, _message: &OutboxMessage) -> Result<(), String> {
+      tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+      self.sent.fetch_add(1, Ordering::SeqCst);
+      Ok(())
+    }
+  }
+
+  fn test_center(name: &str, per_recipient: bool) -> (NotificationCenter, Arc<AtomicU32>) {
+    let sent = Arc::new(AtomicU32::new(0));
+    let outbox = VecPack::load_or_init(std::env::temp_dir().join(format!(
+      "procurement_test_outbox_{}_{}",
+      name,
+      rand::random::<u32>()
+    )))
+    .unwrap();
+    let channel = CountingNotifier {
+      sent: sent.clone(),
+      per_recipient,
+    };
+    (
+      NotificationCenter::new(vec![Box::new(channel)], outbox),
+      sent,
+    )
+  }
+
+  fn alert() -> Alert {
+    Alert {
+      subject: "Teszt".into(),
+      body: "Teszt".into(),
+    }
+  }
+
+  #[test]
+  fn test_attempt_failed() {
+    let mut message = OutboxMessage::default();
+    message.attempt_failed("hiba".into(), 3);
+    assert_eq!(message.attempts, 1);
+    assert_eq!(message.last_error, "hiba");
+    assert!(!message.failed);
+    let delay = message.next_attempt_at - Utc::now();
+    assert!(delay <= chrono::Duration::seconds(30) && delay > chrono::Duration::seconds(25));
+    message.attempt_failed("hiba".into(), 3);
+    message.attempt_failed("hiba".into(), 3);
+    assert!(message.failed);
+    // Delay is capped at one hour
+    for _ in 0..10 {
+      message.attempt_failed("hiba".into(), 20);
+    }
+    assert!(message.next_attempt_at - Utc::now() <= chrono::Duration::seconds(3600));
+  }
+
+  #[tokio::test]
+  async fn test_concurrent_delivery_sends_once() {
+    let (center, sent) = test_center("once", false);
+    center
+      .outbox
+      .lock()
+      .await
+      .insert(OutboxMessage {
+        id: 1,
+        channel: "counting".into(),
+        ..OutboxMessage::default()
+      })
+      .unwrap();
+    tokio::join!(center.deliver_due(), center.deliver_due());
+    assert_eq!(sent.load(Ordering::SeqCst), 1);
+    assert_eq!(center.outbox.lock().await.iter().count(), 0);
+    assert!(center.in_flight.lock().await.is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_enqueue_per_recipient() {
+    let recipients = vec!["a@example.com".to_string(), "b@example.com".to_string()];
+    let (center, sent) = test_center("per_recipient", true);
+    center
+      .enqueue(AlertEvent::CloseFailure, alert(), recipients.clone())
+      .await
+      .unwrap();
+    assert_eq!(sent.load(Ordering::SeqCst), 2);
+
+    let (center, sent) = test_center("grouped", false);
+    center
+      .enqueue(AlertEvent::CloseFailure, alert(), recipients)
+      .await
+      .unwrap();
+    assert_eq!(sent.load(Ordering::SeqCst), 1);
+  }
+}
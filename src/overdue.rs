@@ -0,0 +1,150 @@
+use crate::notification::AlertEvent;
+use crate::notifier::NotificationCenter;
+use crate::prelude::env_or;
+use crate::procurement::Procurement;
+use crate::store::ProcurementStore;
+use chrono::prelude::*;
+use std::collections::BTreeMap;
+
+/// Days after the estimated delivery date before
+/// a procurement is overdue, PROCUREMENT_OVERDUE_GRACE_DAYS
+/// 0 is the default
+pub fn grace_days() -> i64 {
+  env_or("PROCUREMENT_OVERDUE_GRACE_DAYS", 0)
+}
+
+/// Get buyer email addresses by user ID
+/// PROCUREMENT_BUYER_EMAILS is a comma separated list of
+/// user_id:email pairs, e.g. 1:buyer@example.com,2:other@example.com
+fn buyer_emails() -> BTreeMap<u32, String> {
+  std::env::var("PROCUREMENT_BUYER_EMAILS")
+    .unwrap_or_default()
+    .split(',')
+    .filter_map(|pair| {
+      let mut parts = pair.splitn(2, ':');
+      let user_id = parts.next()?.trim().parse::<u32>().ok()?;
+      let email = parts.next()?.trim();
+      match email.is_empty() {
+        true => None,
+        false => Some((user_id, email.to_string())),
+      }
+    })
+    .collect()
+}
+
+/// Render one digest line of an overdue procurement
+fn digest_line(procurement: &Procurement, now: DateTime<Utc>) -> String {
+  let dd = procurement.estimated_delivery_date.unwrap_or(now);
+  format!(
+    "#{} {} - várható érkezés: {}, késés: {} nap",
+    procurement.id,
+    procurement.reference,
+    dd.format("%Y-%m-%d"),
+    (now - dd).num_days()
+  )
+}
+
+/// Queue digest alert with the given overdue procurements
+async fn send_digest(
+  notifications: &NotificationCenter,
+  procurements: &[&Procurement],
+  recipients: Vec<String>,
+  now: DateTime<Utc>,
+) {
+  let lines = procurements
+    .iter()
+    .map(|p| digest_line(p, now))
+    .collect::<Vec<String>>()
+    .join("\n");
+  notifications
+    .send(
+      AlertEvent::OverdueDelivery,
+      &[
+        ("procurements", lines),
+        ("count", procurements.len().to_string()),
+      ],
+      recipients,
+    )
+    .await;
+}
+
+/// Find ordered procurements past their estimated delivery date
+/// and send the overdue digest to their buyers and to purchasing.
+/// Returns the number of overdue procurements
+pub async fn check(procurements: &ProcurementStore, notifications: &NotificationCenter) -> usize {
+  let now = Utc::now();
+  let grace_days = grace_days();
+  let overdue = procurements
+    .read()
+    .await
+    .iter()
+    .map(|p| p.unpack())
+    .filter(|p| p.is_overdue(now, grace_days))
+    .cloned()
+    .collect::<Vec<Procurement>>();
+
+  if overdue.is_empty() {
+    return 0;
+  }
+
+  // Digest to each buyer with their own procurements
+  let buyers = buyer_emails();
+  let mut by_buyer: BTreeMap<u32, Vec<&Procurement>> = BTreeMap::new();
+  for p in &overdue {
+    by_buyer.entry(p.created_by).or_default().push(p);
+  }
+  for (user_id, items) in by_buyer {
+    if let Some(email) = buyers.get(&user_id) {
+      send_digest(notifications, &items, vec![email.clone()], now).await;
+    }
+  }
+
+  // Full digest to purchasing
+  send_digest(
+    notifications,
+    &overdue.iter().collect::<Vec<&Procurement>>(),
+    AlertEvent::OverdueDelivery.recipients(),
+    now,
+  )
+  .await;
+
+  overdue.len()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::procurement::Status;
+  use packman::VecPack;
+
+  fn temp_pack<T: packman::VecPackMember>(name: &str) -> VecPack<T> {
+    VecPack::load_or_init(std::env::temp_dir().join(format!(
+      "procurement_test_{}_{}",
+      name,
+      rand::random::<u32>()
+    )))
+    .unwrap()
+  }
+
+  fn procurement(id: u32, status: Status, delivery_in_days: i64) -> Procurement {
+    let mut p = Procurement::new(id, 1, 1, 1);
+    p.status = status;
+    p.estimated_delivery_date = Some(Utc::now() + chrono::Duration::days(delivery_in_days));
+    p
+  }
+
+  #[tokio::test]
+  async fn test_check() {
+    let mut packs = temp_pack("procurement");
+    packs.insert(procurement(1, Status::Ordered, -3)).unwrap();
+    packs.insert(procurement(2, Status::Ordered, 1)).unwrap();
+    packs.insert(procurement(3, Status::Arrived, -3)).unwrap();
+    packs.insert(procurement(4, Status::Closed, -30)).unwrap();
+    let procurements = ProcurementStore::new(packs);
+    let notifications = NotificationCenter::new(Vec::new(), temp_pack("outbox"));
+
+    // Only the late ordered one, archived procurements are
+    // not in the store and are closed anyway
+    assert_eq!(check(&procurements, &notifications).await, 1);
+  }
+}
@@ -1,14 +1,17 @@
 use gzlib::proto::procurement::{
-  ProcurementInfoObject, ProcurementItem, ProcurementObject, Status, UplCandidate,
+  DependencyHealth, InvoiceItem, JobStatusObject, PriceProposalObject, PriceProposalStatus,
+  ProcurementInfoObject, ProcurementItem, ProcurementObject, PurchaseOrderEmail, Status,
+  StockSummary, UplCandidate, WebhookDeliveryObject, WebhookObject,
 };
 
-use crate::procurement;
+use crate::{downstream, overdue, price_proposal, procurement, scheduler, webhook};
 
 pub enum ServiceError {
   InternalError(String),
   NotFound(String),
   AlreadyExists(String),
   BadRequest(String),
+  Unavailable(String),
 }
 
 impl ServiceError {
@@ -24,6 +27,9 @@ impl ServiceError {
   pub fn bad_request(msg: &str) -> Self {
     ServiceError::BadRequest(msg.to_string())
   }
+  pub fn unavailable(msg: &str) -> Self {
+    ServiceError::Unavailable(msg.to_string())
+  }
 }
 
 impl std::fmt::Display for ServiceError {
@@ -33,6 +39,7 @@ impl std::fmt::Display for ServiceError {
       ServiceError::NotFound(msg) => write!(f, "{}", msg),
       ServiceError::AlreadyExists(msg) => write!(f, "{}", msg),
       ServiceError::BadRequest(msg) => write!(f, "{}", msg),
+      ServiceError::Unavailable(msg) => write!(f, "{}", msg),
     }
   }
 }
@@ -53,6 +60,7 @@ impl From<ServiceError> for ::tonic::Status {
       ServiceError::NotFound(msg) => ::tonic::Status::not_found(msg),
       ServiceError::AlreadyExists(msg) => ::tonic::Status::already_exists(msg),
       ServiceError::BadRequest(msg) => ::tonic::Status::invalid_argument(msg),
+      ServiceError::Unavailable(msg) => ::tonic::Status::unavailable(msg),
     }
   }
 }
@@ -76,9 +84,20 @@ impl From<std::env::VarError> for ServiceError {
 
 impl From<procurement::Procurement> for ProcurementObject {
   fn from(f: procurement::Procurement) -> Self {
+    // Received pieces per destination stock
+    let stock_summary = f
+      .stock_summary()
+      .into_iter()
+      .map(|i| StockSummary {
+        stock_id: i.stock_id,
+        sku: i.sku,
+        piece: i.piece,
+      })
+      .collect::<Vec<StockSummary>>();
     Self {
       id: f.id,
       source_id: f.source_id,
+      stock_id: f.stock_id,
       reference: f.reference,
       estimated_delivery_date: match f.estimated_delivery_date {
         Some(delivery_date) => delivery_date.to_rfc3339(),
@@ -91,6 +110,7 @@ impl From<procurement::Procurement> for ProcurementObject {
           sku: item.sku,
           ordered_amount: item.ordered_amount,
           expected_net_price: item.expected_net_price,
+          actual_net_price: item.actual_net_price.unwrap_or(0),
         })
         .collect::<Vec<ProcurementItem>>(),
       upls: f
@@ -105,8 +125,47 @@ impl From<procurement::Procurement> for ProcurementObject {
             Some(bbefore) => bbefore.to_rfc3339(),
             None => "".to_string(),
           },
+          stock_id: upl.stock_id.unwrap_or(0),
         })
         .collect::<Vec<UplCandidate>>(),
+      stock_summary,
+      reserved_upl_ids: f.reserved_upl_ids,
+      version: f.version,
+      warnings: Vec::new(),
+      purchase_order_emails: f
+        .purchase_order_emails
+        .iter()
+        .map(|e| PurchaseOrderEmail {
+          to: e.to.clone(),
+          sent_at: e.sent_at.to_rfc3339(),
+          success: e.success,
+          error: e.error.clone(),
+        })
+        .collect::<Vec<PurchaseOrderEmail>>(),
+      invoice_number: match &f.invoice {
+        Some(invoice) => invoice.invoice_number.clone(),
+        None => "".to_string(),
+      },
+      invoice_date: match f.invoice.as_ref().and_then(|i| i.invoice_date) {
+        Some(invoice_date) => invoice_date.to_rfc3339(),
+        None => "".to_string(),
+      },
+      invoice_due_date: match f.invoice.as_ref().and_then(|i| i.due_date) {
+        Some(due_date) => due_date.to_rfc3339(),
+        None => "".to_string(),
+      },
+      invoice_items: match &f.invoice {
+        Some(invoice) => invoice
+          .items
+          .iter()
+          .map(|item| InvoiceItem {
+            sku: item.sku,
+            invoiced_amount: item.invoiced_amount,
+            invoiced_net_price: item.invoiced_net_price,
+          })
+          .collect::<Vec<InvoiceItem>>(),
+        None => Vec::new(),
+      },
       status: match f.status {
         procurement::Status::New => Status::New,
         procurement::Status::Ordered => Status::Ordered,
@@ -114,6 +173,11 @@ impl From<procurement::Procurement> for ProcurementObject {
         procurement::Status::Processing => Status::Processing,
         procurement::Status::Closed => Status::Closed,
       } as i32,
+      closed_at: match f.closed_at {
+        Some(closed_at) => closed_at.to_rfc3339(),
+        None => "".to_string(),
+      },
+      awaiting_pricing_skus: f.awaiting_pricing.clone(),
       created_at: f.created_at.to_rfc3339(),
       created_by: f.created_by,
     }
@@ -125,6 +189,7 @@ impl From<procurement::Procurement> for ProcurementInfoObject {
     Self {
       id: p.id,
       source_id: p.source_id,
+      stock_id: p.stock_id,
       sku_count: p.items.len() as u32,
       sku_piece_count: p
         .items
@@ -145,12 +210,41 @@ impl From<procurement::Procurement> for ProcurementInfoObject {
         procurement::Status::Processing => Status::Processing,
         procurement::Status::Closed => Status::Closed,
       } as i32,
+      awaiting_pricing: p.is_awaiting_pricing(),
+      overdue: p.is_overdue(chrono::Utc::now(), overdue::grace_days()),
+      version: p.version,
       created_at: p.created_at.to_rfc3339(),
       created_by: p.created_by,
     }
   }
 }
 
+impl From<price_proposal::PriceProposal> for PriceProposalObject {
+  fn from(p: price_proposal::PriceProposal) -> Self {
+    Self {
+      id: p.id,
+      sku: p.sku,
+      procurement_id: p.procurement_id,
+      net_price: p.net_price,
+      current_retail_net_price: p.current_retail_net_price,
+      proposed_retail_net_price: p.proposed_retail_net_price,
+      vat: p.vat,
+      status: match p.status {
+        price_proposal::ProposalStatus::Pending => PriceProposalStatus::Pending,
+        price_proposal::ProposalStatus::Accepted => PriceProposalStatus::Accepted,
+        price_proposal::ProposalStatus::Rejected => PriceProposalStatus::Rejected,
+        price_proposal::ProposalStatus::Superseded => PriceProposalStatus::Superseded,
+      } as i32,
+      decided_at: match p.decided_at {
+        Some(decided_at) => decided_at.to_rfc3339(),
+        None => "".to_string(),
+      },
+      decided_by: p.decided_by,
+      created_at: p.created_at.to_rfc3339(),
+    }
+  }
+}
+
 // Helper to load service address from env
 pub fn service_address(service_name: &'static str) -> String {
   let addr = std::env::var(service_name).expect(&format!(
@@ -159,3 +253,108 @@ pub fn service_address(service_name: &'static str) -> String {
   ));
   format!("http://{}", addr)
 }
+
+// Helper to create HTTP client for the outgoing calls
+// Timeouts are read from env, so a hanging endpoint
+// cannot block the relays
+pub fn http_client() -> reqwest::Client {
+  reqwest::Client::builder()
+    .timeout(std::time::Duration::from_secs(env_or(
+      "PROCUREMENT_HTTP_TIMEOUT_SECS",
+      10,
+    )))
+    .connect_timeout(std::time::Duration::from_secs(env_or(
+      "PROCUREMENT_HTTP_CONNECT_TIMEOUT_SECS",
+      5,
+    )))
+    .build()
+    .expect("Could not create HTTP client")
+}
+
+// Helper to load optional config value from env
+// Returns the default if the key is missing or cannot be parsed
+pub fn env_or<T: std::str::FromStr>(key: &'static str, default: T) -> T {
+  match std::env::var(key) {
+    Ok(value) => value.parse::<T>().unwrap_or(default),
+    Err(_) => default,
+  }
+}
+
+impl From<scheduler::JobStatus> for JobStatusObject {
+  fn from(j: scheduler::JobStatus) -> Self {
+    let next_run_at = match (&j.schedule, j.running) {
+      (Some(schedule), false) => schedule.next_after(chrono::Utc::now()),
+      _ => None,
+    };
+    Self {
+      name: j.name.to_string(),
+      schedule: match &j.schedule {
+        Some(schedule) => schedule.to_string(),
+        None => "".to_string(),
+      },
+      enabled: j.schedule.is_some(),
+      running: j.running,
+      last_started_at: match j.last_started_at {
+        Some(t) => t.to_rfc3339(),
+        None => "".to_string(),
+      },
+      last_finished_at: match j.last_finished_at {
+        Some(t) => t.to_rfc3339(),
+        None => "".to_string(),
+      },
+      last_success: j.last_success,
+      last_message: j.last_message,
+      next_run_at: match next_run_at {
+        Some(t) => t.to_rfc3339(),
+        None => "".to_string(),
+      },
+    }
+  }
+}
+
+impl From<webhook::WebhookSubscription> for WebhookObject {
+  fn from(s: webhook::WebhookSubscription) -> Self {
+    Self {
+      id: s.id,
+      url: s.url,
+      event_types: s.event_types,
+      created_at: s.created_at.to_rfc3339(),
+      created_by: s.created_by,
+    }
+  }
+}
+
+impl From<webhook::WebhookDelivery> for WebhookDeliveryObject {
+  fn from(d: webhook::WebhookDelivery) -> Self {
+    Self {
+      id: d.id,
+      webhook_id: d.subscription_id,
+      event_id: d.event.id,
+      event_type: d.event.event_type,
+      attempts: d.attempts,
+      last_error: d.last_error,
+      last_attempt_at: match d.last_attempt_at {
+        Some(t) => t.to_rfc3339(),
+        None => "".to_string(),
+      },
+      created_at: d.event.created_at.to_rfc3339(),
+    }
+  }
+}
+
+impl From<downstream::DownstreamStatus> for DependencyHealth {
+  fn from(s: downstream::DownstreamStatus) -> Self {
+    Self {
+      name: s.name.to_string(),
+      available: s.available,
+      state: s.state.to_string(),
+      consecutive_failures: s.consecutive_failures,
+      last_error: s.last_error,
+      last_failure_at: match s.last_failure_at {
+        Some(t) => t.to_rfc3339(),
+        None => "".to_string(),
+      },
+      features: s.features.iter().map(|f| f.to_string()).collect(),
+    }
+  }
+}
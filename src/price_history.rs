@@ -0,0 +1,123 @@
+use crate::procurement::{Procurement, Status};
+use chrono::prelude::*;
+
+/// Purchase price record of a SKU
+/// from a closed procurement
+#[derive(Debug, Clone)]
+pub struct PriceHistoryItem {
+  pub sku: u32,
+  pub source_id: u32,
+  pub procurement_id: u32,
+  pub date: DateTime<Utc>,
+  pub net_price: u32,
+  pub amount: u32,
+}
+
+/// In-memory purchase price index
+/// built from the closed procurements
+#[derive(Debug, Default)]
+pub struct PriceHistory {
+  items: Vec<PriceHistoryItem>,
+}
+
+impl PriceHistory {
+  /// Build price history from procurements
+  pub fn new<'a>(procurements: impl Iterator<Item = &'a Procurement>) -> Self {
+    let mut res = Self::default();
+    procurements.for_each(|p| res.add_procurement(p));
+    res
+  }
+
+  /// Add procurement items to the index
+  /// Only closed procurements and received SKUs are indexed;
+  /// already indexed procurement is replaced
+  pub fn add_procurement(&mut self, procurement: &Procurement) {
+    match procurement.status {
+      Status::Closed => (),
+      _ => return,
+    }
+    // Remove previous records of this procurement
+    self.items.retain(|i| i.procurement_id != procurement.id);
+    let date = procurement.closed_at.unwrap_or(procurement.created_at);
+    procurement.items.iter().for_each(|item| {
+      let amount = procurement.received_amount(item.sku);
+      if amount == 0 {
+        return;
+      }
+      self.items.push(PriceHistoryItem {
+        sku: item.sku,
+        source_id: procurement.source_id,
+        procurement_id: procurement.id,
+        date,
+        net_price: item.get_net_price(),
+        amount,
+      })
+    });
+  }
+
+  /// Get SKU price history, latest first
+  /// If source_id is given, filter by supplier as well
+  pub fn get(&self, sku: u32, source_id: Option<u32>) -> Vec<&PriceHistoryItem> {
+    let mut res = self
+      .items
+      .iter()
+      .filter(|i| i.sku == sku)
+      .filter(|i| match source_id {
+        Some(source_id) => i.source_id == source_id,
+        None => true,
+      })
+      .collect::<Vec<&PriceHistoryItem>>();
+    res.sort_by_key(|i| std::cmp::Reverse(i.date));
+    res
+  }
+
+  /// Get last purchase of a SKU
+  /// Prefers the given supplier, falls back to any supplier
+  pub fn last_purchase(&self, sku: u32, source_id: u32) -> Option<&PriceHistoryItem> {
+    match self.get(sku, Some(source_id)).first().copied() {
+      Some(item) => Some(item),
+      None => self.get(sku, None).first().copied(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::procurement::{ProcurementItem, UplCandidate};
+
+  fn closed(id: u32, source_id: u32, day: u32, received: u32) -> Procurement {
+    let mut p = Procurement::new(id, source_id, 1, 1);
+    p.items.push(ProcurementItem::new(12, 10, 1000 + id));
+    p.items.push(ProcurementItem::new(13, 10, 500));
+    p.upl_candidates.push(UplCandidate {
+      upl_id: id.to_string(),
+      sku: 12,
+      upl_piece: received,
+      ..UplCandidate::default()
+    });
+    p.status = Status::Closed;
+    p.closed_at = Some(format!("2021-03-{:02}T10:00:00Z", day).parse().unwrap());
+    p
+  }
+
+  #[test]
+  fn test_received_amount_is_recorded() {
+    let history = PriceHistory::new([closed(1, 1, 1, 7)].iter());
+    let items = history.get(12, None);
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].amount, 7);
+    // Nothing received from SKU 13
+    assert!(history.get(13, None).is_empty());
+  }
+
+  #[test]
+  fn test_last_purchase() {
+    let procurements = [closed(1, 1, 1, 1), closed(2, 2, 3, 1), closed(3, 1, 2, 1)];
+    let history = PriceHistory::new(procurements.iter());
+    assert_eq!(history.last_purchase(12, 1).unwrap().procurement_id, 3);
+    assert_eq!(history.last_purchase(12, 2).unwrap().procurement_id, 2);
+    // Falls back to any supplier
+    assert_eq!(history.last_purchase(12, 9).unwrap().procurement_id, 2);
+  }
+}
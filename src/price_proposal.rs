@@ -0,0 +1,220 @@
+use chrono::prelude::*;
+use packman::VecPackMember;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use crate::procurement::ProcResult;
+
+/// What to do at close if a SKU has no retail price
+pub enum MissingPriceMode {
+  // Close is refused
+  Fail,
+  // UPLs are created with zero (pending) price,
+  // and a pricing task is created
+  Pending,
+}
+
+impl MissingPriceMode {
+  /// Load mode from PROCUREMENT_MISSING_PRICE_MODE env variable
+  /// "fail" or "pending", fail is the default
+  pub fn from_env() -> Self {
+    match std::env::var("PROCUREMENT_MISSING_PRICE_MODE") {
+      Ok(mode) if mode.to_lowercase() == "pending" => MissingPriceMode::Pending,
+      _ => MissingPriceMode::Fail,
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub enum ProposalStatus {
+  #[default]
+  Pending,
+  Accepted,
+  Rejected,
+  // A newer proposal was created for the same SKU
+  Superseded,
+}
+
+/// Retail price update proposal
+/// created when a procurement cost pushes
+/// the SKU margin below the target
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PriceProposal {
+  pub id: u32,
+  pub sku: u32,
+  pub procurement_id: u32,
+  pub net_price: u32,
+  pub current_retail_net_price: u32,
+  pub proposed_retail_net_price: u32,
+  pub vat: String,
+  pub status: ProposalStatus,
+  pub decided_at: Option<DateTime<Utc>>,
+  pub decided_by: u32,
+  pub created_at: DateTime<Utc>,
+}
+
+impl PriceProposal {
+  /// Create a new pending proposal
+  pub fn new(
+    id: u32,
+    sku: u32,
+    procurement_id: u32,
+    net_price: u32,
+    current_retail_net_price: u32,
+    proposed_retail_net_price: u32,
+    vat: String,
+  ) -> Self {
+    Self {
+      id,
+      sku,
+      procurement_id,
+      net_price,
+      current_retail_net_price,
+      proposed_retail_net_price,
+      vat,
+      status: ProposalStatus::Pending,
+      decided_at: None,
+      decided_by: 0,
+      created_at: Utc::now(),
+    }
+  }
+
+  pub fn is_pending(&self) -> bool {
+    matches!(self.status, ProposalStatus::Pending)
+  }
+
+  /// Try to close proposal with the given status
+  /// Error if its not pending
+  fn decide(&mut self, status: ProposalStatus, decided_by: u32) -> ProcResult<&Self> {
+    if !self.is_pending() {
+      return Err("Az árjavaslat már el lett bírálva!".into());
+    }
+    self.status = status;
+    self.decided_at = Some(Utc::now());
+    self.decided_by = decided_by;
+    Ok(self)
+  }
+
+  /// Try to accept proposal with the finally applied retail price
+  pub fn accept(&mut self, retail_net_price: u32, decided_by: u32) -> ProcResult<&Self> {
+    self.decide(ProposalStatus::Accepted, decided_by)?;
+    self.proposed_retail_net_price = retail_net_price;
+    Ok(self)
+  }
+
+  /// Try to reject proposal
+  pub fn reject(&mut self, decided_by: u32) -> ProcResult<&Self> {
+    self.decide(ProposalStatus::Rejected, decided_by)
+  }
+
+  /// Try to mark proposal as superseded
+  pub fn supersede(&mut self) -> ProcResult<&Self> {
+    self.decide(ProposalStatus::Superseded, 0)
+  }
+}
+
+impl VecPackMember for PriceProposal {
+  type Out = u32;
+
+  fn get_id(&self) -> &Self::Out {
+    &self.id
+  }
+}
+
+impl Default for PriceProposal {
+  fn default() -> Self {
+    Self {
+      id: 0,
+      sku: 0,
+      procurement_id: 0,
+      net_price: 0,
+      current_retail_net_price: 0,
+      proposed_retail_net_price: 0,
+      vat: "".into(),
+      status: ProposalStatus::default(),
+      decided_at: None,
+      decided_by: 0,
+      created_at: Utc::now(),
+    }
+  }
+}
+
+/// Proposals being decided
+/// The decision pushes the price to the pricing service,
+/// so a claimed proposal cannot be decided or superseded meanwhile
+#[derive(Default)]
+pub struct ProposalClaims {
+  ids: Mutex<HashSet<u32>>,
+}
+
+impl ProposalClaims {
+  /// Try to claim a proposal
+  /// Error if it is already claimed
+  pub fn claim(&self, id: u32) -> ProcResult<ProposalClaim<'_>> {
+    if !self.ids.lock().unwrap().insert(id) {
+      return Err("Az árjavaslat elbírálása folyamatban van!".into());
+    }
+    Ok(ProposalClaim { claims: self, id })
+  }
+
+  pub fn is_claimed(&self, id: u32) -> bool {
+    self.ids.lock().unwrap().contains(&id)
+  }
+}
+
+/// Claim of a proposal, released when dropped
+pub struct ProposalClaim<'a> {
+  claims: &'a ProposalClaims,
+  id: u32,
+}
+
+impl Drop for ProposalClaim<'_> {
+  fn drop(&mut self) {
+    self.claims.ids.lock().unwrap().remove(&self.id);
+  }
+}
+
+/// Calculate the retail net price that gives
+/// the target margin percent over the net price
+pub fn proposed_retail_price(net_price: u32, target_margin_percent: f64) -> u32 {
+  // Guard against division by zero or negative
+  let ratio = (100.0 - target_margin_percent).max(1.0) / 100.0;
+  (net_price as f64 / ratio).ceil() as u32
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_proposed_retail_price() {
+    assert_eq!(proposed_retail_price(750, 25.0), 1000);
+    assert_eq!(proposed_retail_price(1000, 0.0), 1000);
+    assert_eq!(proposed_retail_price(1, 25.0), 2);
+    // Margin of 100% or more is capped
+    assert_eq!(proposed_retail_price(10, 120.0), 1000);
+  }
+
+  #[test]
+  fn test_decide_only_pending() {
+    let mut proposal = PriceProposal::new(1, 12, 1, 750, 900, 1000, "27".into());
+    assert!(proposal.accept(1100, 5).is_ok());
+    assert_eq!(proposal.proposed_retail_net_price, 1100);
+    assert!(proposal.reject(5).is_err());
+    assert!(proposal.supersede().is_err());
+    assert!(!proposal.is_pending());
+  }
+
+  #[test]
+  fn test_claim() {
+    let claims = ProposalClaims::default();
+    let claim = claims.claim(1).unwrap();
+    assert!(claims.is_claimed(1));
+    assert!(claims.claim(1).is_err());
+    assert!(claims.claim(2).is_ok());
+    drop(claim);
+    assert!(!claims.is_claimed(1));
+    assert!(claims.claim(1).is_ok());
+  }
+}
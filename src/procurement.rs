@@ -24,13 +24,38 @@ impl Default for Status {
 pub struct Procurement {
   pub id: u32,
   pub source_id: u32,
+  // Destination stock of the received UPLs
+  #[serde(default)]
+  pub stock_id: u32,
   pub reference: String,
   pub estimated_delivery_date: Option<DateTime<Utc>>,
   pub items: Vec<ProcurementItem>,
   pub upl_candidates: Vec<UplCandidate>,
+  // Generated UPL IDs for label printing
+  #[serde(default)]
+  pub reserved_upl_ids: Vec<String>,
+  // Removed UPL candidates for offline sync
+  #[serde(default)]
+  pub removed_upls: Vec<RemovedUpl>,
+  #[serde(default)]
+  pub invoice: Option<Invoice>,
+  // Purchase order emails sent to the supplier
+  #[serde(default)]
+  pub purchase_order_emails: Vec<PurchaseOrderEmail>,
   pub status: Status,
+  #[serde(default)]
+  pub closed_at: Option<DateTime<Utc>>,
+  // SKUs closed without retail price
+  #[serde(default)]
+  pub awaiting_pricing: Vec<u32>,
   pub created_at: DateTime<Utc>,
   pub created_by: u32,
+  // Incremented on every change
+  #[serde(default)]
+  pub version: u32,
+  // Integration events waiting for delivery
+  #[serde(default)]
+  pub outbox: Vec<IntegrationEvent>,
 }
 
 impl Procurement
@@ -38,26 +63,113 @@ where
   Self: Sized,
 {
   /// Create a new procurement object
-  pub fn new(id: u32, source_id: u32, created_by: u32) -> Self {
+  pub fn new(id: u32, source_id: u32, stock_id: u32, created_by: u32) -> Self {
     Self {
       id,
       source_id,
+      stock_id,
       reference: "".into(),
       estimated_delivery_date: None,
       items: Vec::new(),
       upl_candidates: Vec::new(),
+      reserved_upl_ids: Vec::new(),
+      removed_upls: Vec::new(),
+      invoice: None,
+      purchase_order_emails: Vec::new(),
       status: Status::New,
+      closed_at: None,
+      awaiting_pricing: Vec::new(),
       created_at: Utc::now(),
       created_by,
+      version: 0,
+      outbox: Vec::new(),
     }
   }
 
+  /// Increment version after a change
+  pub fn bump_version(&mut self) -> &Self {
+    self.version += 1;
+    self
+  }
+
+  /// Version of the change being made
+  /// It is bumped when the change is recorded
+  fn next_version(&self) -> u32 {
+    self.version + 1
+  }
+
+  /// Check if a UPL change of the given version was made
+  /// after base_version, by an earlier change than the current one
+  fn changed_since(&self, version: u32, base_version: u32) -> bool {
+    version > base_version && version <= self.version
+  }
+
+  /// Create integration event of the current state
+  pub fn integration_event(&self, event_type: &str) -> IntegrationEvent {
+    IntegrationEvent {
+      // Procurement version is unique per change,
+      // creation time tells apart procurements of the same ID
+      id: format!(
+        "procurement-{}-{}-{}",
+        self.id,
+        self.created_at.timestamp_millis(),
+        self.version
+      ),
+      event_type: event_type.to_string(),
+      procurement_id: self.id,
+      version: self.version,
+      payload: serde_json::json!({
+        "procurement_id": self.id,
+        "version": self.version,
+        "source_id": self.source_id,
+        "stock_id": self.stock_id,
+        "reference": self.reference,
+        "status": format!("{:?}", self.status),
+        "sku_count": self.items.len(),
+        "sku_piece_count": self.items.iter().fold(0, |acc, i| acc + i.ordered_amount),
+        "upl_count": self.upl_candidates.len(),
+        "awaiting_pricing": self.awaiting_pricing,
+      })
+      .to_string(),
+      created_at: Utc::now(),
+    }
+  }
+
+  /// Store integration event of the current state
+  /// It is persisted together with the change itself
+  pub fn record_event(&mut self, event_type: &str) -> &Self {
+    let event = self.integration_event(event_type);
+    self.outbox.push(event);
+    self
+  }
+
+  /// Remove delivered integration event
+  pub fn event_delivered(&mut self, event_id: &str) -> &Self {
+    self.outbox.retain(|e| e.id != event_id);
+    self
+  }
+
   /// Set reference
   pub fn set_reference(&mut self, reference: String) -> &Self {
     self.reference = reference;
     self
   }
 
+  /// Try set destination stock
+  /// Error if stock ID is empty, or procurement is already arrived
+  pub fn set_stock_id(&mut self, stock_id: u32) -> ProcResult<&Self> {
+    if stock_id == 0 {
+      return Err("A raktár megadása kötelező!".into());
+    }
+    match self.status {
+      Status::New | Status::Ordered => {
+        self.stock_id = stock_id;
+        Ok(self)
+      }
+      _ => Err("A raktár csak beérkezés előtt módosítható!".into()),
+    }
+  }
+
   /// Set delivery date
   pub fn set_delivery_date(&mut self, delivery_date: Option<DateTime<Utc>>) -> &Self {
     self.estimated_delivery_date = delivery_date;
@@ -101,6 +213,39 @@ where
     Err("A megadott SKU nem szerepel a rendelésben!".into())
   }
 
+  /// Try update SKU actual (received) price
+  /// Only in Arrived or Processing status
+  /// Error if SKU not there
+  pub fn sku_update_actual_price(&mut self, sku: u32, price: Option<u32>) -> ProcResult<&Self> {
+    match self.status {
+      Status::Arrived | Status::Processing => (),
+      _ => return Err(
+        "Tényleges beszerzési ár csak beérkezett vagy feldolgozás alatti beszerzésnél adható meg!"
+          .into(),
+      ),
+    }
+    for item in &mut self.items {
+      if item.sku == sku {
+        item.update_actual_price(price);
+        return Ok(self);
+      }
+    }
+    Err("A megadott SKU nem szerepel a rendelésben!".into())
+  }
+
+  /// Get the items whose actual price differs from
+  /// the expected one by more than the given percent
+  pub fn price_variance(&self, percent: u32) -> Vec<&ProcurementItem> {
+    self
+      .items
+      .iter()
+      .filter(|item| match item.actual_net_price {
+        Some(actual) => exceeds_tolerance(item.expected_net_price, actual, percent),
+        None => false,
+      })
+      .collect()
+  }
+
   /// Try remove SKU
   /// Error if SKU not there
   pub fn sku_remove(&mut self, sku: u32) -> ProcResult<&Self> {
@@ -123,29 +268,131 @@ where
     piece: u32,
     opened_sku: bool,
     best_before: Option<DateTime<Utc>>,
+    stock_id: Option<u32>,
   ) -> ProcResult<&Self> {
     // Check if UPL ID already there
     if self.upl_candidates.iter().any(|c| c.upl_id == upl_id) {
       return Err("Az adott UPL azonosító már a rendelésben szerepel!".into());
     }
     // Push UPL candidate
-    self.upl_candidates.push(UplCandidate::new(
-      upl_id,
-      sku,
-      piece,
-      opened_sku,
-      best_before,
-    )?);
+    let candidate = UplCandidate::new(upl_id, sku, piece, opened_sku, best_before, stock_id)?;
+    self.push_candidate(candidate);
     // Return self ref
     Ok(self)
   }
 
+  /// Add UPL candidate, its reserved ID is used up
+  fn push_candidate(&mut self, candidate: UplCandidate) {
+    self.reserved_upl_ids.retain(|id| *id != candidate.upl_id);
+    self.upl_candidates.push(UplCandidate {
+      version: self.next_version(),
+      ..candidate
+    });
+  }
+
+  /// Remove UPL candidate
+  /// Removal is kept for offline sync conflict resolution
+  fn remove_candidate(&mut self, upl_id: String) {
+    self.upl_candidates.retain(|upl| upl.upl_id != upl_id);
+    self.removed_upls.push(RemovedUpl {
+      upl_id,
+      removed_at: Utc::now(),
+      version: self.next_version(),
+    });
+  }
+
+  /// Drop removals older than the given time
+  /// A device offline for longer may re-add a removed UPL
+  pub fn prune_removed_upls(&mut self, older_than: DateTime<Utc>) -> &Self {
+    self.removed_upls.retain(|r| r.removed_at >= older_than);
+    self
+  }
+
+  /// Check if UPL candidate can be added
+  /// Error if UPL ID already there or SKU is not ordered
+  pub fn upl_check_new(&self, candidate: &UplCandidate) -> ProcResult<()> {
+    if self
+      .upl_candidates
+      .iter()
+      .any(|c| c.upl_id == candidate.upl_id)
+    {
+      return Err("Az adott UPL azonosító már a rendelésben szerepel!".into());
+    }
+    if !self.items.iter().any(|item| item.sku == candidate.sku) {
+      return Err("A megadott SKU nem szerepel a rendelésben!".into());
+    }
+    Ok(())
+  }
+
+  /// Try add a scanned UPL candidate
+  /// Error with the conflict if UPL ID is already there,
+  /// SKU is not ordered or the SKU would be overcounted
+  pub fn upl_scan(&mut self, candidate: UplCandidate) -> Result<&Self, ScanConflict> {
+    if self
+      .upl_candidates
+      .iter()
+      .any(|c| c.upl_id == candidate.upl_id)
+    {
+      return Err(ScanConflict::DuplicateUpl);
+    }
+    let ordered_amount = match self.items.iter().find(|i| i.sku == candidate.sku) {
+      Some(item) => item.ordered_amount,
+      None => return Err(ScanConflict::SkuNotOrdered),
+    };
+    if self.received_amount(candidate.sku) + candidate.get_piece() > ordered_amount {
+      return Err(ScanConflict::Overcount);
+    }
+    self.push_candidate(candidate);
+    Ok(self)
+  }
+
+  /// Try add UPL candidates in bulk
+  /// Candidates are validated one by one (UPL ID, duplicates, SKU);
+  /// if atomic, nothing is added when any of them fails.
+  /// Returns the result of each candidate
+  pub fn upl_add_bulk(
+    &mut self,
+    candidates: Vec<(String, ProcResult<UplCandidate>)>,
+    atomic: bool,
+  ) -> Vec<(String, ProcResult<()>)> {
+    let mut valid: Vec<UplCandidate> = Vec::new();
+    let mut results: Vec<(String, ProcResult<()>)> = Vec::new();
+    for (upl_id, candidate) in candidates {
+      let res = candidate.and_then(|c| {
+        self.upl_check_new(&c)?;
+        // Check duplicates inside the batch
+        if valid.iter().any(|v| v.upl_id == c.upl_id) {
+          return Err("Az adott UPL azonosító többször szerepel!".to_string());
+        }
+        valid.push(c);
+        Ok(())
+      });
+      results.push((upl_id, res));
+    }
+    // If atomic and any failed, do not apply anything
+    if atomic && results.iter().any(|(_, r)| r.is_err()) {
+      return results
+        .into_iter()
+        .map(|(upl_id, r)| {
+          (
+            upl_id,
+            r.and(Err("Nem került rögzítésre, mert a csomag hibás!".into())),
+          )
+        })
+        .collect();
+    }
+    valid.into_iter().for_each(|c| self.push_candidate(c));
+    results
+  }
+
   /// Try update UPL SKU
   /// Error if UPL ID not there
   pub fn upl_update_sku(&mut self, upl_id: &str, sku: u32) -> ProcResult<&Self> {
+    let version = self.next_version();
     for upl in &mut self.upl_candidates {
       if upl.upl_id == upl_id {
         upl.update_sku(sku);
+        upl.version = version;
         return Ok(self);
       }
     }
@@ -155,9 +402,11 @@ where
   /// Try update UPL piece
   /// Error if UPL ID not there
   pub fn upl_update_piece(&mut self, upl_id: &str, piece: u32) -> ProcResult<&Self> {
+    let version = self.next_version();
     for upl in &mut self.upl_candidates {
       if upl.upl_id == upl_id {
         upl.update_piece(piece);
+        upl.version = version;
         return Ok(self);
       }
     }
@@ -171,9 +420,26 @@ where
     upl_id: &str,
     best_before: Option<DateTime<Utc>>,
   ) -> ProcResult<&Self> {
+    let version = self.next_version();
     for upl in &mut self.upl_candidates {
       if upl.upl_id == upl_id {
         upl.update_best_before(best_before);
+        upl.version = version;
+        return Ok(self);
+      }
+    }
+    Err("A megadott UPL azonosító nem szerepel a rendelésben!".into())
+  }
+
+  /// Try update UPL destination stock
+  /// None means the procurement stock
+  /// Error if UPL ID not there
+  pub fn upl_update_stock(&mut self, upl_id: &str, stock_id: Option<u32>) -> ProcResult<&Self> {
+    let version = self.next_version();
+    for upl in &mut self.upl_candidates {
+      if upl.upl_id == upl_id {
+        upl.update_stock(stock_id);
+        upl.version = version;
         return Ok(self);
       }
     }
@@ -193,6 +459,48 @@ where
     Ok(self)
   }
 
+  /// Get the destination stock of a UPL candidate
+  /// Its own stock if set, otherwise the procurement stock
+  pub fn upl_stock_id(&self, upl: &UplCandidate) -> u32 {
+    upl.stock_id.unwrap_or(self.stock_id)
+  }
+
+  /// Received pieces per destination stock and SKU
+  pub fn stock_summary(&self) -> Vec<StockSummaryItem> {
+    let mut res: Vec<StockSummaryItem> = Vec::new();
+    for upl in &self.upl_candidates {
+      let stock_id = self.upl_stock_id(upl);
+      match res
+        .iter_mut()
+        .find(|i| i.stock_id == stock_id && i.sku == upl.sku)
+      {
+        Some(item) => item.piece += upl.get_piece(),
+        None => res.push(StockSummaryItem {
+          stock_id,
+          sku: upl.sku,
+          piece: upl.get_piece(),
+        }),
+      }
+    }
+    res.sort_by_key(|i| (i.stock_id, i.sku));
+    res
+  }
+
+  /// Try reserve UPL IDs for label printing
+  /// Error if procurement is closed, or ID is already there
+  pub fn reserve_upl_ids(&mut self, upl_ids: Vec<String>) -> ProcResult<&Self> {
+    if let Status::Closed = self.status {
+      return Err("Lezárt beszerzéshez nem foglalható UPL azonosító!".into());
+    }
+    for upl_id in upl_ids {
+      if self.reserved_upl_ids.contains(&upl_id) {
+        return Err("Az adott UPL azonosító már foglalva van!".into());
+      }
+      self.reserved_upl_ids.push(upl_id);
+    }
+    Ok(self)
+  }
+
   /// Try remove UPL
   /// Error if UPL ID not there
   pub fn upl_remove(&mut self, upl_id: String) -> ProcResult<&Self> {
@@ -201,11 +509,237 @@ where
       return Err("A megadott UPL azonosító nem szerepel a rendelésben".into());
     }
     // Remove UPL
-    self.upl_candidates.retain(|upl| *upl.upl_id != upl_id);
+    self.remove_candidate(upl_id);
     // Return self ref
     Ok(self)
   }
 
+  /// Replay an offline scanner operation
+  /// base_version is the procurement version the device synced last.
+  /// Conflicts are decided by the server-side change order,
+  /// the device clock is not used:
+  /// - a UPL candidate changed by someone else after base_version
+  ///   is not updated or removed by the operation
+  /// - a UPL removed after base_version is not re-added or updated
+  /// - repeated operations are reported as already applied
+  pub fn upl_sync(&mut self, operation: SyncOperation, base_version: u32) -> SyncOutcome {
+    let upl_id = match &operation {
+      SyncOperation::Add(c) | SyncOperation::Update(c) => c.upl_id.clone(),
+      SyncOperation::Remove(upl_id) => upl_id.clone(),
+    };
+    let removed_later = self
+      .removed_upls
+      .iter()
+      .any(|r| r.upl_id == upl_id && self.changed_since(r.version, base_version));
+    let existing = self.upl_candidates.iter().position(|c| c.upl_id == upl_id);
+    let changed_on_server = match existing {
+      Some(i) => self.changed_since(self.upl_candidates[i].version, base_version),
+      None => false,
+    };
+
+    match (operation, existing) {
+      // Add new UPL candidate
+      (SyncOperation::Add(candidate), None) => {
+        if removed_later {
+          return SyncOutcome::Skipped("A UPL-t időközben eltávolították!".into());
+        }
+        if let Err(e) = self.upl_check_new(&candidate) {
+          return SyncOutcome::Rejected(e);
+        }
+        self.push_candidate(candidate);
+        SyncOutcome::Applied
+      }
+      // Add or update existing UPL candidate
+      (SyncOperation::Add(candidate), Some(i)) | (SyncOperation::Update(candidate), Some(i)) => {
+        let current = &self.upl_candidates[i];
+        if current.sku == candidate.sku
+          && current.upl_piece == candidate.upl_piece
+          && current.opened_sku == candidate.opened_sku
+          && current.best_before == candidate.best_before
+          && current.stock_id == candidate.stock_id
+        {
+          return SyncOutcome::AlreadyApplied;
+        }
+        if changed_on_server {
+          return SyncOutcome::Skipped("A UPL-t időközben egy másik eszköz módosította!".into());
+        }
+        if !self.items.iter().any(|item| item.sku == candidate.sku) {
+          return SyncOutcome::Rejected("A megadott SKU nem szerepel a rendelésben!".into());
+        }
+        self.upl_candidates[i] = UplCandidate {
+          version: self.next_version(),
+          ..candidate
+        };
+        SyncOutcome::Applied
+      }
+      // Update missing UPL candidate
+      (SyncOperation::Update(_), None) => match removed_later {
+        true => SyncOutcome::Skipped("A UPL-t időközben eltávolították!".into()),
+        false => {
+          SyncOutcome::Rejected("A megadott UPL azonosító nem szerepel a rendelésben!".into())
+        }
+      },
+      // Remove existing UPL candidate
+      (SyncOperation::Remove(upl_id), Some(_)) => {
+        if changed_on_server {
+          return SyncOutcome::Skipped("A UPL-t időközben egy másik eszköz módosította!".into());
+        }
+        self.remove_candidate(upl_id);
+        SyncOutcome::Applied
+      }
+      // Remove missing UPL candidate
+      (SyncOperation::Remove(_), None) => SyncOutcome::AlreadyApplied,
+    }
+  }
+
+  /// Record purchase order email send result
+  /// error is None if sending succeeded
+  pub fn add_purchase_order_email(&mut self, to: String, error: Option<String>) -> &Self {
+    self.purchase_order_emails.push(PurchaseOrderEmail {
+      to,
+      sent_at: Utc::now(),
+      success: error.is_none(),
+      error: error.unwrap_or_default(),
+    });
+    self
+  }
+
+  /// Try set supplier invoice header
+  /// Keeps the already recorded invoice items
+  /// Error if procurement is already closed
+  pub fn set_invoice(
+    &mut self,
+    invoice_number: String,
+    invoice_date: Option<DateTime<Utc>>,
+    due_date: Option<DateTime<Utc>>,
+  ) -> ProcResult<&Self> {
+    if let Status::Closed = self.status {
+      return Err("Lezárt beszerzés számlája nem módosítható!".into());
+    }
+    let items = match self.invoice.take() {
+      Some(invoice) => invoice.items,
+      None => Vec::new(),
+    };
+    self.invoice = Some(Invoice {
+      invoice_number,
+      invoice_date,
+      due_date,
+      items,
+    });
+    Ok(self)
+  }
+
+  /// Try set invoiced amount and price for a SKU
+  /// Error if there is no invoice, or SKU not there
+  pub fn invoice_item_set(
+    &mut self,
+    sku: u32,
+    invoiced_amount: u32,
+    invoiced_net_price: u32,
+  ) -> ProcResult<&Self> {
+    if let Status::Closed = self.status {
+      return Err("Lezárt beszerzés számlája nem módosítható!".into());
+    }
+    // Check if SKU is in the procurement
+    if !self.items.iter().any(|item| item.sku == sku) {
+      return Err("A megadott SKU nem szerepel a rendelésben!".into());
+    }
+    let invoice = self
+      .invoice
+      .as_mut()
+      .ok_or("A beszerzéshez még nincs számla rögzítve!".to_string())?;
+    // Update if already there, otherwise push as new
+    match invoice.items.iter_mut().find(|i| i.sku == sku) {
+      Some(item) => {
+        item.invoiced_amount = invoiced_amount;
+        item.invoiced_net_price = invoiced_net_price;
+      }
+      None => invoice.items.push(InvoiceItem {
+        sku,
+        invoiced_amount,
+        invoiced_net_price,
+      }),
+    }
+    Ok(self)
+  }
+
+  /// Get received piece count for a SKU
+  pub fn received_amount(&self, sku: u32) -> u32 {
+    self
+      .upl_candidates
+      .iter()
+      .filter(|upl| upl.sku == sku)
+      .fold(0, |acc, uc| acc + uc.get_piece())
+  }
+
+  /// Three-way match between ordered, received and invoiced
+  /// amounts and prices. Invoiced price is compared with the
+  /// delivery note price if recorded.
+  /// Returns the lines whose difference is above the given
+  /// tolerance percent. Error if no invoice recorded.
+  pub fn three_way_match(&self, tolerance_percent: u32) -> ProcResult<Vec<MatchDifference>> {
+    let invoice = match &self.invoice {
+      Some(invoice) => invoice,
+      None => return Err("Nincs számla rögzítve!".into()),
+    };
+    let res = self
+      .items
+      .iter()
+      .filter_map(|item| {
+        let (invoiced_amount, invoiced_net_price) =
+          match invoice.items.iter().find(|i| i.sku == item.sku) {
+            Some(i) => (i.invoiced_amount, i.invoiced_net_price),
+            None => (0, 0),
+          };
+        let received_amount = self.received_amount(item.sku);
+        let diff = MatchDifference {
+          sku: item.sku,
+          ordered_amount: item.ordered_amount,
+          received_amount,
+          invoiced_amount,
+          net_price: item.get_net_price(),
+          invoiced_net_price,
+        };
+        let over_tolerance =
+          exceeds_tolerance(item.ordered_amount, received_amount, tolerance_percent)
+            || exceeds_tolerance(item.ordered_amount, invoiced_amount, tolerance_percent)
+            || exceeds_tolerance(received_amount, invoiced_amount, tolerance_percent)
+            || exceeds_tolerance(item.get_net_price(), invoiced_net_price, tolerance_percent);
+        match over_tolerance {
+          true => Some(diff),
+          false => None,
+        }
+      })
+      .collect();
+    Ok(res)
+  }
+
+  /// Set SKUs closed without retail price
+  pub fn set_awaiting_pricing(&mut self, skus: Vec<u32>) -> &Self {
+    self.awaiting_pricing = skus;
+    self
+  }
+
+  /// Remove SKU from the awaiting pricing list
+  pub fn pricing_done(&mut self, sku: u32) -> &Self {
+    self.awaiting_pricing.retain(|s| *s != sku);
+    self
+  }
+
+  /// Check if any SKU is still waiting for retail price
+  pub fn is_awaiting_pricing(&self) -> bool {
+    !self.awaiting_pricing.is_empty()
+  }
+
+  /// Check if ordered procurement passed its
+  /// estimated delivery date by more than the grace days
+  pub fn is_overdue(&self, now: DateTime<Utc>, grace_days: i64) -> bool {
+    match (&self.status, self.estimated_delivery_date) {
+      (Status::Ordered, Some(dd)) => dd + chrono::Duration::days(grace_days) < now,
+      _ => false,
+    }
+  }
+
   /// Try set status to ordered
   // , _created_by: String for the future hystory implementation
   pub fn set_status_ordered(&mut self, _created_by: u32) -> ProcResult<&Self> {
@@ -274,6 +808,11 @@ where
     }
     // Set closed status
     self.status = Status::Closed;
+    self.closed_at = Some(Utc::now());
+    // Release the unused reserved UPL IDs,
+    // and removals kept for offline sync
+    self.reserved_upl_ids.clear();
+    self.removed_upls.clear();
     // return self reference
     Ok(self)
   }
@@ -303,13 +842,56 @@ impl Default for Procurement {
     Self {
       id: 0,
       source_id: 0,
+      stock_id: 0,
       reference: "".into(),
       estimated_delivery_date: None,
       items: Vec::new(),
       upl_candidates: Vec::new(),
+      reserved_upl_ids: Vec::new(),
+      removed_upls: Vec::new(),
+      invoice: None,
+      purchase_order_emails: Vec::new(),
       status: Status::default(),
+      closed_at: None,
+      awaiting_pricing: Vec::new(),
       created_at: Utc::now(),
       created_by: 0,
+      version: 0,
+      outbox: Vec::new(),
+    }
+  }
+}
+
+/// Integration event for other services
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IntegrationEvent {
+  // Deduplication ID
+  pub id: String,
+  pub event_type: String,
+  pub procurement_id: u32,
+  pub version: u32,
+  // JSON payload
+  pub payload: String,
+  pub created_at: DateTime<Utc>,
+}
+
+impl VecPackMember for IntegrationEvent {
+  type Out = String;
+
+  fn get_id(&self) -> &Self::Out {
+    &self.id
+  }
+}
+
+impl Default for IntegrationEvent {
+  fn default() -> Self {
+    Self {
+      id: "".into(),
+      event_type: "".into(),
+      procurement_id: 0,
+      version: 0,
+      payload: "".into(),
+      created_at: Utc::now(),
     }
   }
 }
@@ -319,6 +901,10 @@ pub struct ProcurementItem {
   pub sku: u32,
   pub ordered_amount: u32,
   pub expected_net_price: u32,
+  // Price on the delivery note, if it differs
+  // from the expected one
+  #[serde(default)]
+  pub actual_net_price: Option<u32>,
 }
 
 impl ProcurementItem {
@@ -327,6 +913,7 @@ impl ProcurementItem {
       sku,
       ordered_amount,
       expected_net_price,
+      actual_net_price: None,
     }
   }
   pub fn update_ordered_amount(&mut self, new_amount: u32) {
@@ -335,6 +922,117 @@ impl ProcurementItem {
   pub fn update_price(&mut self, new_price: u32) {
     self.expected_net_price = new_price;
   }
+  pub fn update_actual_price(&mut self, new_price: Option<u32>) {
+    self.actual_net_price = new_price;
+  }
+  /// Get the net price we actually pay
+  /// actual price if set, otherwise the expected one
+  pub fn get_net_price(&self) -> u32 {
+    self.actual_net_price.unwrap_or(self.expected_net_price)
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Invoice {
+  pub invoice_number: String,
+  pub invoice_date: Option<DateTime<Utc>>,
+  pub due_date: Option<DateTime<Utc>>,
+  pub items: Vec<InvoiceItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct InvoiceItem {
+  pub sku: u32,
+  pub invoiced_amount: u32,
+  pub invoiced_net_price: u32,
+}
+
+/// Purchase order email send result
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PurchaseOrderEmail {
+  pub to: String,
+  pub sent_at: DateTime<Utc>,
+  pub success: bool,
+  pub error: String,
+}
+
+/// Three-way match result line
+#[derive(Debug, Clone)]
+pub struct MatchDifference {
+  pub sku: u32,
+  pub ordered_amount: u32,
+  pub received_amount: u32,
+  pub invoiced_amount: u32,
+  // Delivery note price if recorded,
+  // otherwise the expected one
+  pub net_price: u32,
+  pub invoiced_net_price: u32,
+}
+
+/// Check if the difference between base and value
+/// is above the given percent of base
+pub fn exceeds_tolerance(base: u32, value: u32, tolerance_percent: u32) -> bool {
+  let diff = (base as i64 - value as i64).abs();
+  diff * 100 > base as i64 * tolerance_percent as i64
+}
+
+/// Removed UPL candidate
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RemovedUpl {
+  pub upl_id: String,
+  pub removed_at: DateTime<Utc>,
+  // Procurement version of the removal
+  #[serde(default)]
+  pub version: u32,
+}
+
+/// Offline scanner operation
+#[derive(Debug, Clone)]
+pub enum SyncOperation {
+  Add(UplCandidate),
+  Update(UplCandidate),
+  Remove(String),
+}
+
+/// Result of a replayed offline scanner operation
+#[derive(Debug, Clone)]
+pub enum SyncOutcome {
+  Applied,
+  // Operation was already applied before
+  AlreadyApplied,
+  // Conflict resolved in favor of the server state
+  Skipped(String),
+  // Invalid operation
+  Rejected(String),
+}
+
+/// Conflict of a scanned UPL candidate
+#[derive(Debug, Clone)]
+pub enum ScanConflict {
+  // UPL ID is already in the procurement
+  DuplicateUpl,
+  // SKU is not in the procurement
+  SkuNotOrdered,
+  // More pieces received than ordered
+  Overcount,
+}
+
+impl std::fmt::Display for ScanConflict {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ScanConflict::DuplicateUpl => write!(f, "Az adott UPL azonosító már a rendelésben szerepel!"),
+      ScanConflict::SkuNotOrdered => write!(f, "A megadott SKU nem szerepel a rendelésben!"),
+      ScanConflict::Overcount => write!(f, "A SKU-ból több érkezett, mint amennyi rendelve lett!"),
+    }
+  }
+}
+
+/// Received pieces of a SKU in a stock
+#[derive(Debug, Clone)]
+pub struct StockSummaryItem {
+  pub stock_id: u32,
+  pub sku: u32,
+  pub piece: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
@@ -349,6 +1047,14 @@ pub struct UplCandidate {
   pub opened_sku: bool,
   // Optional
   pub best_before: Option<DateTime<Utc>>,
+  // Optional destination stock,
+  // overrides the procurement stock
+  #[serde(default)]
+  pub stock_id: Option<u32>,
+  // Procurement version of the last change,
+  // used to resolve conflicts of offline scanner edits
+  #[serde(default)]
+  pub version: u32,
 }
 
 impl UplCandidate {
@@ -358,6 +1064,7 @@ impl UplCandidate {
     upl_piece: u32,
     opened_sku: bool,
     best_before: Option<DateTime<Utc>>,
+    stock_id: Option<u32>,
   ) -> ProcResult<Self> {
     // Check if ID correct to LuhnCheck
     upl_id
@@ -369,6 +1076,8 @@ impl UplCandidate {
       upl_piece,
       opened_sku,
       best_before,
+      stock_id,
+      version: 0,
     })
   }
   pub fn update_sku(&mut self, sku: u32) {
@@ -380,6 +1089,9 @@ impl UplCandidate {
   pub fn update_best_before(&mut self, best_before: Option<DateTime<Utc>>) {
     self.best_before = best_before;
   }
+  pub fn update_stock(&mut self, stock_id: Option<u32>) {
+    self.stock_id = stock_id;
+  }
   pub fn get_piece(&self) -> u32 {
     match self.opened_sku {
       // If its an opened sku, its piece is 1
@@ -389,3 +1101,405 @@ impl UplCandidate {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn arrived(sku: u32, ordered: u32, received: u32, price: u32) -> Procurement {
+    let mut p = Procurement::new(1, 1, 1, 1);
+    p.items.push(ProcurementItem::new(sku, ordered, price));
+    p.upl_candidates.push(UplCandidate {
+      upl_id: "1".into(),
+      sku,
+      upl_piece: received,
+      ..UplCandidate::default()
+    });
+    p.status = Status::Processing;
+    p
+  }
+
+  fn invoice(p: &mut Procurement, sku: u32, amount: u32, price: u32) {
+    p.set_invoice("INV-1".into(), None, None).unwrap();
+    p.invoice_item_set(sku, amount, price).unwrap();
+  }
+
+  fn bulk_candidate(upl_id: &str, sku: u32) -> (String, ProcResult<UplCandidate>) {
+    (
+      upl_id.to_string(),
+      Ok(UplCandidate {
+        upl_id: upl_id.to_string(),
+        sku,
+        upl_piece: 1,
+        ..UplCandidate::default()
+      }),
+    )
+  }
+
+  fn bulk_procurement() -> Procurement {
+    let mut p = Procurement::new(1, 1, 1, 1);
+    p.items.push(ProcurementItem::new(12, 10, 1000));
+    p
+  }
+
+  #[test]
+  fn test_upl_add_bulk_atomic_rejection() {
+    let mut p = bulk_procurement();
+    let results = p.upl_add_bulk(
+      vec![
+        bulk_candidate("1", 12),
+        bulk_candidate("2", 13),
+        ("3".to_string(), Err("A megadott UPL ID hibás!".to_string())),
+      ],
+      true,
+    );
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|(_, r)| r.is_err()));
+    assert!(p.upl_candidates.is_empty());
+  }
+
+  #[test]
+  fn test_upl_add_bulk_partial() {
+    let mut p = bulk_procurement();
+    p.upl_add_bulk(vec![bulk_candidate("1", 12)], false);
+    let results = p.upl_add_bulk(
+      vec![
+        bulk_candidate("1", 12),
+        bulk_candidate("2", 12),
+        bulk_candidate("3", 13),
+        bulk_candidate("4", 12),
+      ],
+      false,
+    );
+    let ok = results
+      .iter()
+      .filter(|(_, r)| r.is_ok())
+      .map(|(id, _)| id.as_str())
+      .collect::<Vec<&str>>();
+    assert_eq!(ok, vec!["2", "4"]);
+    assert_eq!(
+      p.upl_candidates
+        .iter()
+        .map(|c| c.upl_id.as_str())
+        .collect::<Vec<&str>>(),
+      vec!["1", "2", "4"]
+    );
+  }
+
+  #[test]
+  fn test_upl_add_bulk_duplicates_in_batch() {
+    let mut p = bulk_procurement();
+    let results = p.upl_add_bulk(
+      vec![bulk_candidate("1", 12), bulk_candidate("1", 12)],
+      false,
+    );
+    assert!(results[0].1.is_ok());
+    assert!(results[1].1.is_err());
+    assert_eq!(p.upl_candidates.len(), 1);
+
+    let mut p = bulk_procurement();
+    let results = p.upl_add_bulk(vec![bulk_candidate("1", 12), bulk_candidate("1", 12)], true);
+    assert!(results.iter().all(|(_, r)| r.is_err()));
+    assert!(p.upl_candidates.is_empty());
+  }
+
+  #[test]
+  fn test_is_overdue() {
+    let mut p = Procurement::new(1, 1, 1, 1);
+    let dd: DateTime<Utc> = "2026-10-10T08:00:00Z".parse().unwrap();
+    p.estimated_delivery_date = Some(dd);
+    p.status = Status::Ordered;
+    // Late only after the grace days are over
+    assert!(!p.is_overdue("2026-10-12T08:00:00Z".parse().unwrap(), 2));
+    assert!(p.is_overdue("2026-10-12T08:00:01Z".parse().unwrap(), 2));
+    assert!(!p.is_overdue(dd, 0));
+    assert!(p.is_overdue("2026-10-10T08:00:01Z".parse().unwrap(), 0));
+    // Only ordered procurements are overdue
+    p.status = Status::Closed;
+    assert!(!p.is_overdue("2026-11-10T08:00:00Z".parse().unwrap(), 0));
+    p.status = Status::Arrived;
+    assert!(!p.is_overdue("2026-11-10T08:00:00Z".parse().unwrap(), 0));
+  }
+
+  #[test]
+  fn test_awaiting_pricing() {
+    let mut p = Procurement::new(1, 1, 1, 1);
+    assert!(!p.is_awaiting_pricing());
+    p.set_awaiting_pricing(vec![1, 2]);
+    assert!(p.is_awaiting_pricing());
+    p.pricing_done(1);
+    assert_eq!(p.awaiting_pricing, vec![2]);
+    // Unknown SKU does not change it
+    p.pricing_done(3);
+    assert!(p.is_awaiting_pricing());
+    p.pricing_done(2);
+    assert!(!p.is_awaiting_pricing());
+  }
+
+  #[test]
+  fn test_integration_event_id() {
+    let mut p = Procurement::new(1, 1, 1, 1);
+    let mut reused = Procurement::new(1, 1, 1, 1);
+    reused.created_at = p.created_at + chrono::Duration::seconds(1);
+    assert_ne!(
+      p.integration_event("procurement.created").id,
+      reused.integration_event("procurement.created").id
+    );
+    let id = p.integration_event("procurement.created").id;
+    p.bump_version();
+    assert_ne!(p.integration_event("procurement.updated").id, id);
+  }
+
+  #[test]
+  fn test_load_baseline_procurement() {
+    let json = r#"{
+      "id": 7,
+      "source_id": 3,
+      "reference": "R-1",
+      "estimated_delivery_date": null,
+      "items": [{ "sku": 12, "ordered_amount": 5, "expected_net_price": 1000 }],
+      "upl_candidates": [{
+        "upl_id": "1000000006",
+        "sku": 12,
+        "upl_piece": 5,
+        "opened_sku": false,
+        "best_before": null
+      }],
+      "status": "Processing",
+      "created_at": "2021-03-01T10:00:00Z",
+      "created_by": 1
+    }"#;
+    let p: Procurement = serde_json::from_str(json).unwrap();
+    assert_eq!(p.id, 7);
+    assert_eq!(p.stock_id, 0);
+    assert_eq!(p.version, 0);
+    assert!(p.invoice.is_none());
+    assert!(p.outbox.is_empty());
+    assert!(p.reserved_upl_ids.is_empty());
+    assert_eq!(p.items[0].actual_net_price, None);
+    assert_eq!(p.items[0].get_net_price(), 1000);
+    assert_eq!(p.upl_candidates[0].stock_id, None);
+    assert_eq!(p.upl_candidates[0].version, 0);
+  }
+
+  #[test]
+  fn test_exceeds_tolerance() {
+    assert!(!exceeds_tolerance(100, 100, 0));
+    assert!(exceeds_tolerance(100, 101, 0));
+    assert!(!exceeds_tolerance(100, 105, 5));
+    assert!(exceeds_tolerance(100, 106, 5));
+    assert!(exceeds_tolerance(100, 94, 5));
+    assert!(exceeds_tolerance(0, 1, 50));
+    assert!(!exceeds_tolerance(0, 0, 0));
+  }
+
+  #[test]
+  fn test_three_way_match_without_invoice() {
+    let p = arrived(12, 5, 3, 1000);
+    assert!(p.three_way_match(0).is_err());
+  }
+
+  #[test]
+  fn test_three_way_match_amounts() {
+    let mut p = arrived(12, 5, 5, 1000);
+    invoice(&mut p, 12, 5, 1000);
+    assert!(p.three_way_match(0).unwrap().is_empty());
+
+    let mut p = arrived(12, 5, 4, 1000);
+    invoice(&mut p, 12, 5, 1000);
+    let diff = p.three_way_match(0).unwrap();
+    assert_eq!(diff.len(), 1);
+    assert_eq!(diff[0].received_amount, 4);
+    assert_eq!(diff[0].invoiced_amount, 5);
+    // 20% difference is inside 25% tolerance
+    assert!(p.three_way_match(25).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_three_way_match_uses_actual_price() {
+    let mut p = arrived(12, 5, 5, 1000);
+    invoice(&mut p, 12, 5, 1100);
+    assert_eq!(p.three_way_match(0).unwrap().len(), 1);
+
+    // Delivery note price matches the invoice
+    p.sku_update_actual_price(12, Some(1100)).unwrap();
+    assert!(p.three_way_match(0).unwrap().is_empty());
+
+    p.sku_update_actual_price(12, Some(1050)).unwrap();
+    let diff = p.three_way_match(0).unwrap();
+    assert_eq!(diff.len(), 1);
+    assert_eq!(diff[0].net_price, 1050);
+    assert_eq!(diff[0].invoiced_net_price, 1100);
+  }
+
+  #[test]
+  fn test_set_stock_id() {
+    let mut p = Procurement::new(1, 1, 1, 1);
+    assert!(p.set_stock_id(0).is_err());
+    assert!(p.set_stock_id(2).is_ok());
+    assert_eq!(p.stock_id, 2);
+    p.status = Status::Arrived;
+    assert!(p.set_stock_id(3).is_err());
+    assert_eq!(p.stock_id, 2);
+  }
+
+  #[test]
+  fn test_stock_summary() {
+    let mut p = arrived(12, 10, 2, 1000);
+    p.upl_candidates.push(UplCandidate {
+      upl_id: "2".into(),
+      sku: 12,
+      upl_piece: 3,
+      stock_id: Some(5),
+      ..UplCandidate::default()
+    });
+    let summary = p
+      .stock_summary()
+      .iter()
+      .map(|i| (i.stock_id, i.sku, i.piece))
+      .collect::<Vec<(u32, u32, u32)>>();
+    assert_eq!(summary, vec![(1, 12, 2), (5, 12, 3)]);
+
+    // Cleared override falls back to the procurement stock
+    p.upl_update_stock("2", None).unwrap();
+    assert_eq!(p.stock_summary()[0].piece, 5);
+  }
+
+  #[test]
+  fn test_reserved_upl_ids() {
+    let mut p = arrived(12, 10, 2, 1000);
+    p.reserve_upl_ids(vec!["2".into(), "3".into()]).unwrap();
+    assert!(p.reserve_upl_ids(vec!["3".into()]).is_err());
+
+    // Reservation is used up by the scanned UPL
+    p.upl_scan(UplCandidate {
+      upl_id: "2".into(),
+      sku: 12,
+      upl_piece: 8,
+      ..UplCandidate::default()
+    })
+    .unwrap();
+    assert_eq!(p.reserved_upl_ids, vec!["3".to_string()]);
+
+    // Unused reservations are released on close
+    p.set_status_closed(1).unwrap();
+    assert!(p.reserved_upl_ids.is_empty());
+    assert!(p.reserve_upl_ids(vec!["4".into()]).is_err());
+  }
+
+  fn scan(upl_id: &str, piece: u32) -> UplCandidate {
+    UplCandidate {
+      upl_id: upl_id.into(),
+      sku: 12,
+      upl_piece: piece,
+      ..UplCandidate::default()
+    }
+  }
+
+  /// Replay operations of a device as one change
+  fn sync(p: &mut Procurement, base_version: u32, ops: Vec<SyncOperation>) -> Vec<SyncOutcome> {
+    let res = ops
+      .into_iter()
+      .map(|op| p.upl_sync(op, base_version))
+      .collect();
+    p.bump_version();
+    res
+  }
+
+  #[test]
+  fn test_upl_sync_apply_and_repeat() {
+    let mut p = arrived(12, 10, 1, 1000);
+    let ops = vec![
+      SyncOperation::Add(scan("2", 2)),
+      SyncOperation::Update(scan("2", 3)),
+      SyncOperation::Add(scan("3", 1)),
+      SyncOperation::Remove("3".into()),
+    ];
+    let res = sync(&mut p, 0, ops.clone());
+    assert!(res.iter().all(|r| matches!(r, SyncOutcome::Applied)));
+    assert_eq!(p.received_amount(12), 4);
+
+    // Resent batch after a lost response
+    let res = sync(&mut p, 0, ops);
+    assert!(matches!(res[1], SyncOutcome::AlreadyApplied));
+    assert!(matches!(res[3], SyncOutcome::AlreadyApplied));
+    assert_eq!(p.received_amount(12), 4);
+  }
+
+  #[test]
+  fn test_upl_sync_conflicts() {
+    let mut p = arrived(12, 10, 1, 1000);
+    sync(&mut p, 0, vec![SyncOperation::Add(scan("2", 2))]);
+    sync(&mut p, 0, vec![SyncOperation::Add(scan("3", 2))]);
+    let base_version = p.version;
+
+    // Changes of other devices after the base version
+    sync(
+      &mut p,
+      base_version,
+      vec![
+        SyncOperation::Update(scan("2", 5)),
+        SyncOperation::Remove("3".into()),
+      ],
+    );
+
+    let res = sync(
+      &mut p,
+      base_version,
+      vec![
+        SyncOperation::Update(scan("2", 4)),
+        SyncOperation::Remove("2".into()),
+        SyncOperation::Update(scan("3", 4)),
+        SyncOperation::Add(scan("3", 4)),
+        SyncOperation::Update(scan("9", 1)),
+        SyncOperation::Add(UplCandidate {
+          sku: 99,
+          ..scan("4", 1)
+        }),
+      ],
+    );
+    assert!(matches!(res[0], SyncOutcome::Skipped(_)));
+    assert!(matches!(res[1], SyncOutcome::Skipped(_)));
+    assert!(matches!(res[2], SyncOutcome::Skipped(_)));
+    assert!(matches!(res[3], SyncOutcome::Skipped(_)));
+    assert!(matches!(res[4], SyncOutcome::Rejected(_)));
+    assert!(matches!(res[5], SyncOutcome::Rejected(_)));
+    assert_eq!(p.upl_candidates[1].upl_piece, 5);
+
+    // Device up to date wins
+    let base_version = p.version;
+    let res = sync(
+      &mut p,
+      base_version,
+      vec![SyncOperation::Remove("2".into())],
+    );
+    assert!(matches!(res[0], SyncOutcome::Applied));
+  }
+
+  #[test]
+  fn test_prune_removed_upls() {
+    let mut p = arrived(12, 10, 1, 1000);
+    p.upl_remove("1".into()).unwrap();
+    p.removed_upls[0].removed_at = Utc::now() - chrono::Duration::days(10);
+    p.prune_removed_upls(Utc::now() - chrono::Duration::days(7));
+    assert!(p.removed_upls.is_empty());
+  }
+
+  #[test]
+  fn test_price_variance() {
+    let mut p = arrived(12, 5, 5, 1000);
+    p.items.push(ProcurementItem::new(13, 1, 0));
+    p.items.push(ProcurementItem::new(14, 1, 2000));
+    assert!(p.price_variance(5).is_empty());
+
+    p.sku_update_actual_price(12, Some(1050)).unwrap();
+    p.sku_update_actual_price(13, Some(500)).unwrap();
+    p.sku_update_actual_price(14, Some(2200)).unwrap();
+    let skus = p
+      .price_variance(5)
+      .iter()
+      .map(|i| i.sku)
+      .collect::<Vec<u32>>();
+    assert_eq!(skus, vec![13, 14]);
+  }
+}
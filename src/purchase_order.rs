@@ -0,0 +1,137 @@
+use crate::procurement::Procurement;
+use crate::template;
+use gzlib::proto::product::SkuObj;
+
+/// Default purchase order HTML template
+/// Can be overridden by the file in PROCUREMENT_PO_TEMPLATE
+pub const DEFAULT_TEMPLATE: &str = "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Megrendelés #{{procurement_id}}</title>
+<style>
+body { font-family: sans-serif; font-size: 14px; }
+table { border-collapse: collapse; width: 100%; }
+th, td { border: 1px solid #999; padding: 4px 8px; }
+td.num { text-align: right; }
+</style>
+</head>
+<body>
+<h1>Megrendelés #{{procurement_id}}</h1>
+<p>Hivatkozás: {{reference}}</p>
+<p>Várható szállítási dátum: {{estimated_delivery_date}}</p>
+<p>Kelt: {{date}}</p>
+<table>
+<tr><th>SKU</th><th>Megnevezés</th><th>Mennyiség</th><th>Nettó egységár</th><th>Nettó összesen</th></tr>
+{{lines}}
+<tr><td colspan=\"4\">Összesen</td><td class=\"num\">{{total_net_price}} Ft</td></tr>
+</table>
+</body>
+</html>
+";
+
+/// Default purchase order line template
+/// Can be overridden by the file in PROCUREMENT_PO_LINE_TEMPLATE
+pub const DEFAULT_LINE_TEMPLATE: &str = "<tr><td>{{sku}}</td><td>{{sku_name}}</td><td class=\"num\">{{ordered_amount}} {{unit}}</td><td class=\"num\">{{net_price}} Ft</td><td class=\"num\">{{line_net_price}} Ft</td></tr>
+";
+
+/// Escape HTML special characters
+fn escape(value: &str) -> String {
+  value
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+/// Net price of an order line
+/// u64, as amount times price can exceed u32
+fn line_net_price(amount: u32, net_price: u32) -> u64 {
+  amount as u64 * net_price as u64
+}
+
+/// Render purchase order document of a procurement
+/// SKU objects are used for SKU names and units.
+/// Only HTML is produced; PDF is made by the client
+/// (print to PDF), there is no PDF renderer in the service.
+pub fn render(procurement: &Procurement, skus: &[SkuObj]) -> Result<String, String> {
+  let document_template = template::load("PROCUREMENT_PO_TEMPLATE", DEFAULT_TEMPLATE)?;
+  let line_template = template::load("PROCUREMENT_PO_LINE_TEMPLATE", DEFAULT_LINE_TEMPLATE)?;
+
+  let lines = procurement
+    .items
+    .iter()
+    .map(|item| {
+      let sku = skus.iter().find(|s| s.sku == item.sku);
+      template::render(
+        &line_template,
+        &[
+          ("sku", item.sku.to_string()),
+          (
+            "sku_name",
+            escape(&sku.map(|s| s.display_name.clone()).unwrap_or_default()),
+          ),
+          (
+            "unit",
+            escape(&sku.map(|s| s.unit.clone()).unwrap_or_default()),
+          ),
+          ("ordered_amount", item.ordered_amount.to_string()),
+          ("net_price", item.expected_net_price.to_string()),
+          (
+            "line_net_price",
+            line_net_price(item.ordered_amount, item.expected_net_price).to_string(),
+          ),
+        ],
+      )
+    })
+    .collect::<Vec<String>>()
+    .join("");
+
+  let total_net_price = procurement.items.iter().fold(0, |acc, item| {
+    acc + line_net_price(item.ordered_amount, item.expected_net_price)
+  });
+
+  Ok(template::render(
+    &document_template,
+    &[
+      ("procurement_id", procurement.id.to_string()),
+      ("source_id", procurement.source_id.to_string()),
+      ("reference", escape(&procurement.reference)),
+      (
+        "estimated_delivery_date",
+        match procurement.estimated_delivery_date {
+          Some(dd) => dd.format("%Y-%m-%d").to_string(),
+          None => "-".to_string(),
+        },
+      ),
+      ("date", chrono::Utc::now().format("%Y-%m-%d").to_string()),
+      ("lines", lines),
+      ("total_net_price", total_net_price.to_string()),
+    ],
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::procurement::ProcurementItem;
+
+  #[test]
+  fn test_render_large_amounts() {
+    let mut p = Procurement::new(1, 1, 1, 1);
+    p.set_reference("<R&1>".into());
+    p.items.push(ProcurementItem::new(12, 5000, 1_000_000));
+    p.items.push(ProcurementItem::new(13, 1, 5));
+    let skus = vec![SkuObj {
+      sku: 12,
+      display_name: "Alma <b>".into(),
+      unit: "kg".into(),
+      ..SkuObj::default()
+    }];
+    let document = render(&p, &skus).unwrap();
+    assert!(document.contains("5000000000 Ft"));
+    assert!(document.contains("5000000005 Ft"));
+    assert!(document.contains("Alma &lt;b&gt;"));
+    assert!(document.contains("&lt;R&amp;1&gt;"));
+  }
+}
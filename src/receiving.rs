@@ -0,0 +1,221 @@
+use crate::change_feed::{self, ChangeFeed};
+use crate::downstream::Downstream;
+use crate::procurement::{ProcResult, ScanConflict, SyncOperation, UplCandidate};
+use crate::store::{ProcurementStore, RESERVED_ELSEWHERE};
+use chrono::{DateTime, Utc};
+use gzlib::proto::procurement::{
+  ChangeKind, ScanAck, ScanConflictKind, ScanEvent, ScanOperation, ScanOperationKind,
+};
+use gzlib::proto::product::{product_client::ProductClient, GetSkuByBarcodeRequest};
+use std::collections::HashMap;
+use tonic::transport::Channel;
+
+/// Resolve a scanned SKU barcode through the product service
+/// Resolved barcodes are cached for the session
+pub async fn resolve_barcode(
+  client_product: &Downstream<ProductClient<Channel>>,
+  cache: &mut HashMap<String, u32>,
+  barcode: &str,
+) -> ProcResult<u32> {
+  if let Some(sku) = cache.get(barcode) {
+    return Ok(*sku);
+  }
+  let sku = client_product
+    .call(|mut client| {
+      let barcode = barcode.to_string();
+      async move {
+        client
+          .get_sku_by_barcode(GetSkuByBarcodeRequest { barcode })
+          .await
+      }
+    })
+    .await
+    .map_err(|e| format!("A vonalkód nem azonosítható! {}", e))?
+    .into_inner()
+    .sku;
+  cache.insert(barcode.to_string(), sku);
+  Ok(sku)
+}
+
+/// Process a single scan event of a receiving session
+/// Tries to add the scanned UPL candidate to the procurement,
+/// and returns the acknowledgement with the running SKU totals.
+/// SKU is resolved from the barcode if given.
+pub async fn process_scan(
+  procurements: &ProcurementStore,
+  changes: &ChangeFeed,
+  client_product: &Downstream<ProductClient<Channel>>,
+  barcodes: &mut HashMap<String, u32>,
+  mut event: ScanEvent,
+) -> ScanAck {
+  if !event.barcode.is_empty() {
+    match resolve_barcode(client_product, barcodes, &event.barcode).await {
+      // SKU ID, if sent as well, must match the barcode
+      Ok(sku) if event.sku == 0 || event.sku == sku => event.sku = sku,
+      res => {
+        return ScanAck {
+          seq: event.seq,
+          upl_id: event.upl_id,
+          accepted: false,
+          conflict: ScanConflictKind::InvalidData as i32,
+          message: match res {
+            Err(e) => e,
+            Ok(_) => "A vonalkód nem a megadott SKU-hoz tartozik!".to_string(),
+          },
+          sku: event.sku,
+          ordered_amount: 0,
+          received_amount: 0,
+        }
+      }
+    }
+  }
+
+  let mut ack = ScanAck {
+    seq: event.seq,
+    upl_id: event.upl_id.clone(),
+    accepted: false,
+    conflict: ScanConflictKind::NoConflict as i32,
+    message: "".to_string(),
+    sku: event.sku,
+    ordered_amount: 0,
+    received_amount: 0,
+  };
+
+  // Process bestbefore date
+  let bdate: Option<DateTime<Utc>> = match event.best_before.len() {
+    // If a not empty string, then try to parse as rfc3339
+    x if x > 0 => match DateTime::parse_from_rfc3339(&event.best_before) {
+      Ok(date) => Some(date.with_timezone(&Utc)),
+      Err(_) => {
+        ack.conflict = ScanConflictKind::InvalidData as i32;
+        ack.message = "A megadott lejárati dátum hibás!".to_string();
+        return ack;
+      }
+    },
+    // If empty string then None
+    _ => None,
+  };
+
+  // Create candidate, it checks the UPL ID
+  let candidate = match UplCandidate::new(
+    event.upl_id,
+    event.sku,
+    event.piece,
+    event.opened_sku,
+    bdate,
+    // 0 means the procurement stock
+    match event.stock_id {
+      0 => None,
+      x => Some(x),
+    },
+  ) {
+    Ok(candidate) => candidate,
+    Err(e) => {
+      ack.conflict = ScanConflictKind::InvalidData as i32;
+      ack.message = e;
+      return ack;
+    }
+  };
+
+  let mut locked = match procurements.lock(event.procurement_id).await {
+    Ok(locked) => locked,
+    Err(e) => {
+      ack.conflict = ScanConflictKind::InvalidData as i32;
+      ack.message = e.to_string();
+      return ack;
+    }
+  };
+
+  // UPL ID reserved by another procurement cannot be used
+  // Checked and saved under the write lock
+  let mut packs = procurements.write().await;
+  if !locked
+    .reserved_elsewhere(&packs, std::slice::from_ref(&candidate.upl_id))
+    .is_empty()
+  {
+    ack.conflict = ScanConflictKind::DuplicateUpl as i32;
+    ack.message = RESERVED_ELSEWHERE.to_string();
+    return ack;
+  }
+
+  let procurement = &mut locked.procurement;
+  match procurement.upl_scan(candidate) {
+    Ok(_) => ack.accepted = true,
+    Err(conflict) => {
+      ack.message = conflict.to_string();
+      ack.conflict = match conflict {
+        ScanConflict::DuplicateUpl => ScanConflictKind::DuplicateUpl,
+        ScanConflict::SkuNotOrdered => ScanConflictKind::SkuNotOrdered,
+        ScanConflict::Overcount => ScanConflictKind::Overcount,
+      } as i32;
+    }
+  }
+
+  // Running totals of the scanned SKU
+  if let Some(item) = procurement.items.iter().find(|i| i.sku == ack.sku) {
+    ack.ordered_amount = item.ordered_amount;
+  }
+  ack.received_amount = procurement.received_amount(ack.sku);
+
+  // Store and publish change while the procurement is locked
+  if ack.accepted {
+    let res = change_feed::record(&mut locked.procurement, ChangeKind::UplChanged);
+    match locked.save_to(&mut packs) {
+      Ok(_) => {
+        drop(packs);
+        changes.publish(ChangeKind::UplChanged, &res).await
+      }
+      Err(e) => {
+        ack.accepted = false;
+        ack.conflict = ScanConflictKind::InvalidData as i32;
+        ack.message = e.to_string();
+      }
+    }
+  }
+
+  ack
+}
+
+/// Parse an offline scanner operation to replay
+/// Device timestamp is informative only, it is not used for ordering
+pub fn parse_operation(op: ScanOperation) -> ProcResult<SyncOperation> {
+  let upl_candidate = op.upl_candidate.unwrap_or_default();
+
+  let kind = ScanOperationKind::from_i32(op.kind).ok_or("Nem létező művelet!".to_string())?;
+
+  // Remove needs the UPL ID only
+  if let ScanOperationKind::Remove = kind {
+    return Ok(SyncOperation::Remove(upl_candidate.upl_id));
+  }
+
+  // Process bestbefore date
+  let bdate: Option<DateTime<Utc>> = match upl_candidate.best_before.len() {
+    // If a not empty string, then try to parse as rfc3339
+    x if x > 0 => Some(
+      DateTime::parse_from_rfc3339(&upl_candidate.best_before)
+        .map_err(|_| "A megadott lejárati dátum hibás!".to_string())?
+        .with_timezone(&Utc),
+    ),
+    // If empty string then None
+    _ => None,
+  };
+
+  // Create candidate, it checks the UPL ID
+  let candidate = UplCandidate::new(
+    upl_candidate.upl_id,
+    upl_candidate.sku,
+    upl_candidate.upl_piece,
+    upl_candidate.opened_sku,
+    bdate,
+    // 0 means the procurement stock
+    match upl_candidate.stock_id {
+      0 => None,
+      x => Some(x),
+    },
+  )?;
+
+  match kind {
+    ScanOperationKind::Add => Ok(SyncOperation::Add(candidate)),
+    _ => Ok(SyncOperation::Update(candidate)),
+  }
+}
@@ -0,0 +1,431 @@
+use chrono::prelude::*;
+use std::{collections::BTreeMap, future::Future, pin::Pin, sync::Arc};
+use tokio::sync::Mutex;
+
+/// Cron-like schedule in UTC
+/// Five fields: minute hour day-of-month month day-of-week
+/// Each field is *, a number, a range (1-5), a step (*/15, 0-30/10)
+/// or a comma separated list of these. Day of week 0 or 7 is Sunday.
+/// As in standard cron, if both day fields are restricted,
+/// either of them may match.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+  source: String,
+  minute: u64,
+  hour: u64,
+  day: u64,
+  month: u64,
+  weekday: u64,
+  // Day fields starting with *
+  day_any: bool,
+  weekday_any: bool,
+}
+
+/// Parse one schedule field into a bitmask
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+  let mut res = 0u64;
+  for part in field.split(',') {
+    let (range, step) = match part.split_once('/') {
+      Some((range, step)) => (
+        range,
+        step
+          .parse::<u32>()
+          .map_err(|_| format!("Hibás lépésköz: {}", part))?,
+      ),
+      None => (part, 1),
+    };
+    if step == 0 {
+      return Err(format!("Hibás lépésköz: {}", part));
+    }
+    let (from, to) = match range {
+      "*" => (min, max),
+      _ => match range.split_once('-') {
+        Some((from, to)) => (
+          from
+            .parse::<u32>()
+            .map_err(|_| format!("Hibás érték: {}", part))?,
+          to.parse::<u32>()
+            .map_err(|_| format!("Hibás érték: {}", part))?,
+        ),
+        None => {
+          let value = range
+            .parse::<u32>()
+            .map_err(|_| format!("Hibás érték: {}", part))?;
+          // a/n means from a to max
+          match part.contains('/') {
+            true => (value, max),
+            false => (value, value),
+          }
+        }
+      },
+    };
+    if from < min || to > max || from > to {
+      return Err(format!(
+        "Érték a tartományon kívül: {} ({}-{})",
+        part, min, max
+      ));
+    }
+    let mut value = from;
+    while value <= to {
+      res |= 1 << value;
+      value += step;
+    }
+  }
+  Ok(res)
+}
+
+impl Schedule {
+  /// Parse schedule expression
+  pub fn parse(expr: &str) -> Result<Self, String> {
+    let fields = expr.split_whitespace().collect::<Vec<&str>>();
+    if fields.len() != 5 {
+      return Err(format!("Az ütemezés 5 mezőből áll: {}", expr));
+    }
+    let mut weekday = parse_field(fields[4], 0, 7)?;
+    // 7 is Sunday as well
+    if weekday & (1 << 7) != 0 {
+      weekday |= 1;
+    }
+    Ok(Self {
+      source: expr.to_string(),
+      minute: parse_field(fields[0], 0, 59)?,
+      hour: parse_field(fields[1], 0, 23)?,
+      day: parse_field(fields[2], 1, 31)?,
+      month: parse_field(fields[3], 1, 12)?,
+      weekday,
+      day_any: fields[2].starts_with('*'),
+      weekday_any: fields[4].starts_with('*'),
+    })
+  }
+
+  /// Check if schedule is due on the given date
+  fn matches_date(&self, date: NaiveDate) -> bool {
+    let day = self.day & (1 << date.day()) != 0;
+    let weekday = self.weekday & (1 << date.weekday().num_days_from_sunday()) != 0;
+    let day_matches = match (self.day_any, self.weekday_any) {
+      (false, false) => day || weekday,
+      _ => day && weekday,
+    };
+    self.month & (1 << date.month()) != 0 && day_matches
+  }
+
+  /// Check if schedule is due in the given minute
+  pub fn matches(&self, time: DateTime<Utc>) -> bool {
+    self.minute & (1 << time.minute()) != 0
+      && self.hour & (1 << time.hour()) != 0
+      && self.matches_date(time.naive_utc().date())
+  }
+
+  /// Get the next due minute after the given time
+  /// Walks day by day, so a rare schedule (e.g. 29th February)
+  /// is found as well. None if there is no due minute within 8 years.
+  pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let start = time.with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+    let mut date = start.naive_utc().date();
+    for day in 0..366 * 8 {
+      if self.matches_date(date) {
+        // Only the first day starts later than midnight
+        let (from_hour, from_minute) = match day {
+          0 => (start.hour(), start.minute()),
+          _ => (0, 0),
+        };
+        for hour in (from_hour..24).filter(|h| self.hour & (1 << h) != 0) {
+          let from_minute = if hour == from_hour { from_minute } else { 0 };
+          let minutes = self.minute >> from_minute << from_minute;
+          if minutes != 0 {
+            let time = date.and_hms_opt(hour, minutes.trailing_zeros(), 0)?;
+            return Some(Utc.from_utc_datetime(&time));
+          }
+        }
+      }
+      date = date.succ_opt()?;
+    }
+    None
+  }
+}
+
+impl std::fmt::Display for Schedule {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.source)
+  }
+}
+
+/// Last run and outcome of a job
+#[derive(Debug, Clone)]
+pub struct JobStatus {
+  pub name: &'static str,
+  // None if job is disabled
+  pub schedule: Option<Schedule>,
+  pub running: bool,
+  pub last_started_at: Option<DateTime<Utc>>,
+  pub last_finished_at: Option<DateTime<Utc>>,
+  pub last_success: bool,
+  // Job result or error message
+  pub last_message: String,
+}
+
+/// Shared job status registry
+pub type JobRegistry = Arc<Mutex<BTreeMap<&'static str, JobStatus>>>;
+
+type JobFuture = Pin<Box<dyn Future<Output = Result<String, String>> + Send>>;
+
+/// Marks the job finished when dropped,
+/// so a panicking job does not stay running forever
+struct RunGuard {
+  name: &'static str,
+  registry: JobRegistry,
+  // None until the job returns
+  result: Option<Result<String, String>>,
+}
+
+impl Drop for RunGuard {
+  fn drop(&mut self) {
+    let result = self
+      .result
+      .take()
+      .unwrap_or_else(|| Err("A feladat futása megszakadt!".to_string()));
+    if let Err(e) = &result {
+      eprintln!("Job {} failed: {}", self.name, e);
+    }
+    let (name, registry) = (self.name, self.registry.clone());
+    tokio::task::spawn(async move {
+      if let Some(status) = registry.lock().await.get_mut(name) {
+        status.running = false;
+        status.last_finished_at = Some(Utc::now());
+        status.last_success = result.is_ok();
+        status.last_message = match result {
+          Ok(msg) => msg,
+          Err(e) => e,
+        };
+      }
+    });
+  }
+}
+
+struct Job {
+  name: &'static str,
+  schedule: Schedule,
+  run: Arc<dyn Fn() -> JobFuture + Send + Sync>,
+}
+
+/// Runs the registered jobs by their schedules
+#[derive(Default)]
+pub struct Scheduler {
+  jobs: Vec<Job>,
+  registry: JobRegistry,
+}
+
+impl Scheduler {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Get job status registry
+  pub fn registry(&self) -> JobRegistry {
+    self.registry.clone()
+  }
+
+  /// Register job
+  /// Schedule is read from the env key, default is used if not set.
+  /// "off" disables the job.
+  pub async fn add<F, Fut>(
+    &mut self,
+    name: &'static str,
+    schedule_env_key: &'static str,
+    default_schedule: &str,
+    run: F,
+  ) where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<String, String>> + Send + 'static,
+  {
+    let expr = std::env::var(schedule_env_key).unwrap_or(default_schedule.to_string());
+    let schedule = match expr.trim() {
+      "off" | "" => None,
+      expr => match Schedule::parse(expr) {
+        Ok(schedule) => Some(schedule),
+        Err(e) => {
+          eprintln!("Invalid schedule for job {}, job disabled: {}", name, e);
+          None
+        }
+      },
+    };
+    self.registry.lock().await.insert(
+      name,
+      JobStatus {
+        name,
+        schedule: schedule.clone(),
+        running: false,
+        last_started_at: None,
+        last_finished_at: None,
+        last_success: false,
+        last_message: "".to_string(),
+      },
+    );
+    if let Some(schedule) = schedule {
+      self.jobs.push(Job {
+        name,
+        schedule,
+        run: Arc::new(move || Box::pin(run()) as JobFuture),
+      });
+    }
+  }
+
+  /// Start scheduler loop in the background
+  /// Jobs are checked at the start of every minute;
+  /// a job is skipped while its previous run is still running
+  pub fn start(self) {
+    tokio::task::spawn(async move {
+      loop {
+        let now = Utc::now();
+        let next = match now.with_second(0).and_then(|t| t.with_nanosecond(0)) {
+          Some(t) => t + chrono::Duration::minutes(1),
+          None => now + chrono::Duration::minutes(1),
+        };
+        tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+
+        for job in self.jobs.iter().filter(|j| j.schedule.matches(next)) {
+          {
+            let mut registry = self.registry.lock().await;
+            if let Some(status) = registry.get_mut(job.name) {
+              if status.running {
+                eprintln!("Job {} is still running, skipped", job.name);
+                continue;
+              }
+              status.running = true;
+              status.last_started_at = Some(Utc::now());
+            }
+          }
+          let (name, run, registry) = (job.name, job.run.clone(), self.registry.clone());
+          tokio::task::spawn(async move {
+            let mut guard = RunGuard {
+              name,
+              registry,
+              result: None,
+            };
+            guard.result = Some(run().await);
+          });
+        }
+      }
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn at(time: &str) -> DateTime<Utc> {
+    time.parse().unwrap()
+  }
+
+  #[test]
+  fn test_parse() {
+    assert!(Schedule::parse("*/15 2 * * 1-5").is_ok());
+    assert!(Schedule::parse("0,30 8-18/2 1 1,6 7").is_ok());
+    assert!(Schedule::parse("* * * *").is_err());
+    assert!(Schedule::parse("60 * * * *").is_err());
+    assert!(Schedule::parse("* 24 * * *").is_err());
+    assert!(Schedule::parse("* * 0 * *").is_err());
+    assert!(Schedule::parse("* * * 13 *").is_err());
+    assert!(Schedule::parse("*/0 * * * *").is_err());
+    assert!(Schedule::parse("5-1 * * * *").is_err());
+    assert!(Schedule::parse("a * * * *").is_err());
+  }
+
+  #[test]
+  fn test_matches() {
+    let schedule = Schedule::parse("*/15 2 * * 1-5").unwrap();
+    // 2021-03-01 is Monday
+    assert!(schedule.matches(at("2021-03-01T02:30:00Z")));
+    assert!(!schedule.matches(at("2021-03-01T02:31:00Z")));
+    assert!(!schedule.matches(at("2021-03-01T03:30:00Z")));
+    assert!(!schedule.matches(at("2021-03-06T02:30:00Z")));
+    // 7 is Sunday
+    let schedule = Schedule::parse("0 0 * * 7").unwrap();
+    assert!(schedule.matches(at("2021-03-07T00:00:00Z")));
+  }
+
+  #[test]
+  fn test_matches_day_fields() {
+    // Both day fields restricted: either matches
+    let schedule = Schedule::parse("0 0 1 * 1").unwrap();
+    assert!(schedule.matches(at("2021-03-01T00:00:00Z")));
+    assert!(schedule.matches(at("2021-03-08T00:00:00Z")));
+    assert!(schedule.matches(at("2021-04-01T00:00:00Z")));
+    assert!(!schedule.matches(at("2021-03-02T00:00:00Z")));
+    // Only day of month restricted
+    let schedule = Schedule::parse("0 0 1 * *").unwrap();
+    assert!(schedule.matches(at("2021-04-01T00:00:00Z")));
+    assert!(!schedule.matches(at("2021-03-08T00:00:00Z")));
+    // Star with step is still unrestricted
+    let schedule = Schedule::parse("0 0 */2 * 1").unwrap();
+    assert!(schedule.matches(at("2021-03-01T00:00:00Z")));
+    assert!(!schedule.matches(at("2021-03-03T00:00:00Z")));
+  }
+
+  #[test]
+  fn test_next_after() {
+    let schedule = Schedule::parse("*/15 2 * * 1-5").unwrap();
+    assert_eq!(
+      schedule.next_after(at("2021-03-01T02:15:00Z")),
+      Some(at("2021-03-01T02:30:00Z"))
+    );
+    assert_eq!(
+      schedule.next_after(at("2021-03-01T02:14:59Z")),
+      Some(at("2021-03-01T02:15:00Z"))
+    );
+    // Friday after the last run goes to Monday
+    assert_eq!(
+      schedule.next_after(at("2021-03-05T02:45:00Z")),
+      Some(at("2021-03-08T02:00:00Z"))
+    );
+    let schedule = Schedule::parse("30 23 31 12 *").unwrap();
+    assert_eq!(
+      schedule.next_after(at("2021-03-01T00:00:00Z")),
+      Some(at("2021-12-31T23:30:00Z"))
+    );
+    // Leap day is more than a year away
+    let schedule = Schedule::parse("0 0 29 2 *").unwrap();
+    assert_eq!(
+      schedule.next_after(at("2021-03-01T00:00:00Z")),
+      Some(at("2024-02-29T00:00:00Z"))
+    );
+    // Never due
+    let schedule = Schedule::parse("0 0 31 2 *").unwrap();
+    assert_eq!(schedule.next_after(at("2021-03-01T00:00:00Z")), None);
+  }
+
+  #[tokio::test]
+  async fn test_run_guard_on_panic() {
+    let registry = JobRegistry::default();
+    registry.lock().await.insert(
+      "test",
+      JobStatus {
+        name: "test",
+        schedule: None,
+        running: true,
+        last_started_at: Some(Utc::now()),
+        last_finished_at: None,
+        last_success: true,
+        last_message: "".to_string(),
+      },
+    );
+    let job_registry = registry.clone();
+    let res = tokio::task::spawn(async move {
+      let _guard = RunGuard {
+        name: "test",
+        registry: job_registry,
+        result: None,
+      };
+      panic!("job panicked");
+    })
+    .await;
+    assert!(res.is_err());
+    tokio::task::yield_now().await;
+    let registry = registry.lock().await;
+    let status = registry.get("test").unwrap();
+    assert!(!status.running);
+    assert!(!status.last_success);
+    assert!(status.last_finished_at.is_some());
+  }
+}
@@ -0,0 +1,215 @@
+use crate::prelude::{ServiceError, ServiceResult};
+use crate::procurement::Procurement;
+use packman::VecPack;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex as SyncMutex};
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+pub const RESERVED_ELSEWHERE: &str = "Az adott UPL azonosító egy másik beszerzéshez van foglalva!";
+
+/// Procurement store
+/// Queries share a read lock. Changes of a procurement are serialized
+/// by its own lock and are made on a copy, so the store is write locked
+/// only while the changed procurement is written back.
+/// No lock may be held across downstream calls.
+pub struct ProcurementStore {
+  packs: RwLock<VecPack<Procurement>>,
+  // Per procurement locks; the map is only locked for the lookup.
+  // Entries are never removed, so a waiter of a removed procurement
+  // and a new one of the same ID share the same lock
+  locks: SyncMutex<HashMap<u32, Arc<Mutex<()>>>>,
+  // Procurements claimed by a long running operation
+  claims: SyncMutex<HashSet<u32>>,
+}
+
+impl ProcurementStore {
+  pub fn new(db: VecPack<Procurement>) -> Self {
+    Self {
+      packs: RwLock::new(db),
+      locks: SyncMutex::new(HashMap::new()),
+      claims: SyncMutex::new(HashSet::new()),
+    }
+  }
+
+  /// Shared access for queries
+  pub async fn read(&self) -> RwLockReadGuard<'_, VecPack<Procurement>> {
+    self.packs.read().await
+  }
+
+  /// Exclusive access for inserts, removes and
+  /// checks across procurements. Keep it short.
+  pub async fn write(&self) -> RwLockWriteGuard<'_, VecPack<Procurement>> {
+    self.packs.write().await
+  }
+
+  /// Get a copy of a procurement
+  pub async fn get(&self, id: u32) -> ServiceResult<Procurement> {
+    Ok(self.packs.read().await.find_id(&id)?.unpack().clone())
+  }
+
+  async fn lock_of(&self, id: u32) -> OwnedMutexGuard<()> {
+    let lock = self
+      .locks
+      .lock()
+      .unwrap()
+      .entry(id)
+      .or_insert_with(|| Arc::new(Mutex::new(())))
+      .clone();
+    lock.lock_owned().await
+  }
+
+  fn is_claimed(&self, id: u32) -> bool {
+    self.claims.lock().unwrap().contains(&id)
+  }
+
+  /// Lock a procurement for change
+  /// Fails if it is claimed by a long running operation
+  pub async fn lock(&self, id: u32) -> ServiceResult<Locked<'_>> {
+    let guard = self.lock_of(id).await;
+    if self.is_claimed(id) {
+      return Err(ServiceError::bad_request(
+        "A beszerzésen egy másik művelet van folyamatban! Kérem próbálja újra később.",
+      ));
+    }
+    Ok(Locked {
+      store: self,
+      procurement: self.get(id).await?,
+      _guard: guard,
+    })
+  }
+
+  /// Claim a procurement for a long running operation
+  /// Other changes are rejected until the claim is dropped,
+  /// while no lock is held during the operation
+  pub async fn claim(&self, id: u32) -> ServiceResult<Claim<'_>> {
+    let _guard = self.lock_of(id).await;
+    self.packs.read().await.find_id(&id)?;
+    if !self.claims.lock().unwrap().insert(id) {
+      return Err(ServiceError::bad_request(
+        "A beszerzésen egy másik művelet van folyamatban! Kérem próbálja újra később.",
+      ));
+    }
+    Ok(Claim { store: self, id })
+  }
+
+  /// Lock a claimed procurement to finish the operation
+  /// Claim is released under the lock, so no other change gets between
+  pub async fn lock_claimed(&self, claim: Claim<'_>) -> ServiceResult<Locked<'_>> {
+    let id = claim.id;
+    let guard = self.lock_of(id).await;
+    drop(claim);
+    Ok(Locked {
+      store: self,
+      procurement: self.get(id).await?,
+      _guard: guard,
+    })
+  }
+
+  /// Remove a procurement
+  /// Caller must hold its lock
+  pub async fn remove(&self, locked: Locked<'_>) -> ServiceResult<()> {
+    self
+      .packs
+      .write()
+      .await
+      .remove_pack(&locked.procurement.id)?;
+    Ok(())
+  }
+}
+
+/// Locked copy of a procurement
+/// Changes are stored by save; the lock is released when dropped
+pub struct Locked<'a> {
+  store: &'a ProcurementStore,
+  pub procurement: Procurement,
+  _guard: OwnedMutexGuard<()>,
+}
+
+impl Locked<'_> {
+  /// Write the changed procurement back to the store
+  pub async fn save(&self) -> ServiceResult<()> {
+    self.save_to(&mut *self.store.packs.write().await)
+  }
+
+  /// Write back under an already held write lock
+  /// for changes checked against the other procurements
+  pub fn save_to(&self, packs: &mut VecPack<Procurement>) -> ServiceResult<()> {
+    *packs.find_id_mut(&self.procurement.id)?.as_mut().unpack() = self.procurement.clone();
+    Ok(())
+  }
+
+  /// Get the given UPL IDs that are reserved by any other procurement
+  /// Check under the write lock the change is saved with by save_to,
+  /// so they cannot be reserved meanwhile
+  pub fn reserved_elsewhere(
+    &self,
+    packs: &VecPack<Procurement>,
+    upl_ids: &[String],
+  ) -> Vec<String> {
+    packs
+      .iter()
+      .filter(|p| p.unpack().id != self.procurement.id)
+      .flat_map(|p| p.unpack().reserved_upl_ids.iter())
+      .filter(|upl_id| upl_ids.contains(upl_id))
+      .cloned()
+      .collect()
+  }
+}
+
+/// Claim of a procurement, released when dropped
+pub struct Claim<'a> {
+  store: &'a ProcurementStore,
+  id: u32,
+}
+
+impl Drop for Claim<'_> {
+  fn drop(&mut self) {
+    self.store.claims.lock().unwrap().remove(&self.id);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_remove_keeps_lock() {
+    let mut packs = VecPack::load_or_init(
+      std::env::temp_dir().join(format!("procurement_test_store_{}", rand::random::<u32>())),
+    )
+    .unwrap();
+    packs.insert(Procurement::new(1, 1, 1, 1)).unwrap();
+    let store = ProcurementStore::new(packs);
+    let lock = store.locks.lock().unwrap().entry(1).or_default().clone();
+
+    let locked = store.lock(1).await.unwrap();
+    store.remove(locked).await.unwrap();
+    assert!(store.get(1).await.is_err());
+    // A new procurement of the same ID gets the same lock
+    let kept = store.locks.lock().unwrap().get(&1).cloned().unwrap();
+    assert!(Arc::ptr_eq(&lock, &kept));
+  }
+
+  #[tokio::test]
+  async fn test_reserved_elsewhere() {
+    let mut packs = VecPack::load_or_init(
+      std::env::temp_dir().join(format!("procurement_test_store_{}", rand::random::<u32>())),
+    )
+    .unwrap();
+    let mut reserving = Procurement::new(1, 1, 1, 1);
+    reserving
+      .reserve_upl_ids(vec!["1".to_string(), "2".to_string()])
+      .unwrap();
+    packs.insert(reserving).unwrap();
+    let mut own = Procurement::new(2, 1, 1, 1);
+    own.reserve_upl_ids(vec!["3".to_string()]).unwrap();
+    packs.insert(own).unwrap();
+    let store = ProcurementStore::new(packs);
+
+    let locked = store.lock(2).await.unwrap();
+    let packs = store.write().await;
+    let upl_ids = ["2".to_string(), "3".to_string(), "4".to_string()];
+    // Own reservations can be used
+    assert_eq!(locked.reserved_elsewhere(&packs, &upl_ids), vec!["2"]);
+  }
+}
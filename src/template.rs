@@ -0,0 +1,73 @@
+use std::path::PathBuf;
+
+/// Load template from the file given in the env variable
+/// If the env variable is not set, returns the default template.
+/// File is read on every call, so template changes are applied
+/// without restarting the service.
+pub fn load(path_env_key: &'static str, default: &str) -> Result<String, String> {
+  match std::env::var(path_env_key) {
+    Ok(path) => std::fs::read_to_string(PathBuf::from(&path))
+      .map_err(|e| format!("A sablon nem olvasható: {}, {}", path, e)),
+    Err(_) => Ok(default.to_string()),
+  }
+}
+
+/// Render template by replacing the {{key}} placeholders
+/// with their values. Unknown placeholders are left as they are.
+/// Rendered in a single pass, so placeholders inside the
+/// inserted values are not replaced.
+pub fn render(template: &str, values: &[(&str, String)]) -> String {
+  let mut res = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(start) = rest.find("{{") {
+    res.push_str(&rest[..start]);
+    let tail = &rest[start + 2..];
+    match tail.find("}}") {
+      Some(end) => {
+        let key = &tail[..end];
+        match values.iter().find(|(k, _)| *k == key) {
+          Some((_, value)) => res.push_str(value),
+          None => res.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &tail[end + 2..];
+      }
+      // Unclosed placeholder
+      None => {
+        res.push_str(&rest[start..]);
+        rest = "";
+      }
+    }
+  }
+  res.push_str(rest);
+  res
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render() {
+    let values = [("name", "Alma".to_string()), ("piece", "3".to_string())];
+    assert_eq!(render("{{name}}: {{piece}} db", &values), "Alma: 3 db");
+    assert_eq!(render("{{name}}{{name}}", &values), "AlmaAlma");
+    assert_eq!(render("nincs", &values), "nincs");
+  }
+
+  #[test]
+  fn test_render_unknown_and_unclosed() {
+    let values = [("name", "Alma".to_string())];
+    assert_eq!(render("{{other}} {{name}}", &values), "{{other}} Alma");
+    assert_eq!(render("{{name}} {{name", &values), "Alma {{name");
+    assert_eq!(render("{{{name}}}", &values), "{{{name}}}");
+  }
+
+  #[test]
+  fn test_render_values_are_not_expanded() {
+    let values = [
+      ("name", "{{total}}".to_string()),
+      ("total", "1000".to_string()),
+    ];
+    assert_eq!(render("{{name}} {{total}}", &values), "{{total}} 1000");
+  }
+}
@@ -0,0 +1,135 @@
+use rand::Rng;
+use std::collections::HashSet;
+
+/// Shortest allowed generated UPL ID, check digit included
+pub const MIN_LENGTH: usize = 6;
+
+/// Random attempts per requested ID before giving up
+const ATTEMPTS_PER_ID: usize = 100;
+
+/// Load generated UPL ID length from PROCUREMENT_UPL_ID_LENGTH env variable
+/// 8 is the default; error if it is invalid or shorter than MIN_LENGTH
+pub fn length_from_env() -> Result<usize, String> {
+  let length = match std::env::var("PROCUREMENT_UPL_ID_LENGTH") {
+    Ok(value) => value
+      .parse::<usize>()
+      .map_err(|_| format!("Invalid PROCUREMENT_UPL_ID_LENGTH: {}", value))?,
+    Err(_) => 8,
+  };
+  if length < MIN_LENGTH {
+    return Err(format!(
+      "PROCUREMENT_UPL_ID_LENGTH must be at least {}, got {}",
+      MIN_LENGTH, length
+    ));
+  }
+  Ok(length)
+}
+
+/// Calculate Luhn check digit for a numeric string
+pub fn luhn_check_digit(digits: &str) -> u32 {
+  let sum = digits
+    .chars()
+    .rev()
+    .filter_map(|c| c.to_digit(10))
+    .enumerate()
+    .fold(0, |acc, (i, d)| {
+      // Double every second digit from the right,
+      // starting with the rightmost one
+      acc
+        + match i % 2 {
+          0 => match d * 2 {
+            x if x > 9 => x - 9,
+            x => x,
+          },
+          _ => d,
+        }
+    });
+  (10 - sum % 10) % 10
+}
+
+/// Generate a random Luhn valid numeric ID
+/// length includes the check digit
+pub fn generate(length: usize) -> String {
+  let mut rng = rand::thread_rng();
+  // First digit is never 0
+  let mut base = rng.gen_range(1..10).to_string();
+  while base.len() < length.max(2) - 1 {
+    base.push_str(&rng.gen_range(0..10).to_string());
+  }
+  let check_digit = luhn_check_digit(&base);
+  format!("{}{}", base, check_digit)
+}
+
+/// Generate count unique IDs that are not in the taken set
+/// Error if the free IDs of the length are running out
+pub fn generate_unique(
+  count: usize,
+  length: usize,
+  taken: &HashSet<String>,
+) -> Result<Vec<String>, String> {
+  let mut res: HashSet<String> = HashSet::new();
+  let mut attempts = count.saturating_mul(ATTEMPTS_PER_ID);
+  while res.len() < count {
+    if attempts == 0 {
+      return Err(format!(
+        "Nem sikerült {} hosszú szabad UPL azonosítót generálni! Növelje a PROCUREMENT_UPL_ID_LENGTH értékét!",
+        length
+      ));
+    }
+    attempts -= 1;
+    let id = generate(length);
+    if !taken.contains(&id) {
+      res.insert(id);
+    }
+  }
+  Ok(res.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use gzlib::id::LuhnCheck;
+
+  #[test]
+  fn test_luhn_check_digit() {
+    assert_eq!(luhn_check_digit("7992739871"), 3);
+    assert_eq!(luhn_check_digit("100000000"), 8);
+    assert_eq!(luhn_check_digit("0"), 0);
+  }
+
+  #[test]
+  fn test_generate() {
+    for length in MIN_LENGTH..12 {
+      let id = generate(length);
+      assert_eq!(id.len(), length);
+      assert!(!id.starts_with('0'));
+      assert!(id.luhn_check_ref().is_ok());
+    }
+  }
+
+  #[test]
+  fn test_generate_unique() {
+    let taken = generate_unique(50, MIN_LENGTH, &HashSet::new())
+      .unwrap()
+      .into_iter()
+      .collect::<HashSet<String>>();
+    assert_eq!(taken.len(), 50);
+    let res = generate_unique(50, MIN_LENGTH, &taken).unwrap();
+    assert_eq!(res.iter().collect::<HashSet<&String>>().len(), 50);
+    assert!(res.iter().all(|id| !taken.contains(id)));
+  }
+
+  #[test]
+  fn test_generate_unique_exhausted() {
+    // Every valid ID of the shortest length is taken
+    let taken = (10_000..100_000)
+      .map(|base: u32| {
+        let base = base.to_string();
+        let check_digit = luhn_check_digit(&base);
+        format!("{}{}", base, check_digit)
+      })
+      .collect::<HashSet<String>>();
+    assert!(generate_unique(1, MIN_LENGTH, &taken).is_err());
+    assert!(generate_unique(1, MIN_LENGTH + 1, &taken).is_ok());
+  }
+}
@@ -0,0 +1,336 @@
+use crate::integration::EventSink;
+use crate::prelude::{env_or, http_client};
+use crate::procurement::IntegrationEvent;
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use packman::{VecPack, VecPackMember};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::sync::Mutex;
+
+/// Partner webhook subscription
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookSubscription {
+  pub id: u32,
+  pub url: String,
+  // Subscribed event types, empty means every event
+  pub event_types: Vec<String>,
+  // HMAC-SHA256 signing secret
+  pub secret: String,
+  pub created_at: DateTime<Utc>,
+  pub created_by: u32,
+}
+
+impl WebhookSubscription {
+  /// Check if subscription wants the event type
+  pub fn wants(&self, event_type: &str) -> bool {
+    self.event_types.is_empty() || self.event_types.iter().any(|t| t == event_type)
+  }
+}
+
+impl VecPackMember for WebhookSubscription {
+  type Out = u32;
+
+  fn get_id(&self) -> &Self::Out {
+    &self.id
+  }
+}
+
+impl Default for WebhookSubscription {
+  fn default() -> Self {
+    Self {
+      id: 0,
+      url: "".into(),
+      event_types: Vec::new(),
+      secret: "".into(),
+      created_at: Utc::now(),
+      created_by: 0,
+    }
+  }
+}
+
+/// Event delivery to a subscription
+/// Dead delivery reached the max attempts,
+/// it waits for inspection and replay
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookDelivery {
+  // Event ID and subscription ID
+  pub id: String,
+  pub subscription_id: u32,
+  pub event: IntegrationEvent,
+  pub attempts: u32,
+  pub last_error: String,
+  pub last_attempt_at: Option<DateTime<Utc>>,
+  pub next_attempt_at: DateTime<Utc>,
+  pub dead: bool,
+}
+
+impl WebhookDelivery {
+  pub fn new(subscription_id: u32, event: IntegrationEvent) -> Self {
+    Self {
+      id: format!("{}-{}", event.id, subscription_id),
+      subscription_id,
+      event,
+      attempts: 0,
+      last_error: "".into(),
+      last_attempt_at: None,
+      next_attempt_at: Utc::now(),
+      dead: false,
+    }
+  }
+
+  /// Register a failed attempt
+  /// Next attempt is scheduled with exponential backoff
+  pub fn attempt_failed(&mut self, error: String, max_attempts: u32) -> &Self {
+    self.attempts += 1;
+    self.last_error = error;
+    self.last_attempt_at = Some(Utc::now());
+    // 10s, 20s, 40s .. max 1 hour
+    let delay = (10 * 2i64.pow(self.attempts.min(9) - 1)).min(3600);
+    self.next_attempt_at = Utc::now() + chrono::Duration::seconds(delay);
+    self.dead = self.attempts >= max_attempts;
+    self
+  }
+
+  /// Try to replay dead delivery
+  pub fn replay(&mut self) -> Result<&Self, String> {
+    if !self.dead {
+      return Err("Csak sikertelen kézbesítés küldhető újra!".into());
+    }
+    self.dead = false;
+    self.attempts = 0;
+    self.next_attempt_at = Utc::now();
+    Ok(self)
+  }
+}
+
+impl VecPackMember for WebhookDelivery {
+  type Out = String;
+
+  fn get_id(&self) -> &Self::Out {
+    &self.id
+  }
+}
+
+impl Default for WebhookDelivery {
+  fn default() -> Self {
+    Self::new(0, IntegrationEvent::default())
+  }
+}
+
+/// Sign body with the subscription secret
+/// Returns hex encoded HMAC-SHA256
+pub fn sign(secret: &str, body: &str) -> String {
+  let mut mac =
+    Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+  mac.update(body.as_bytes());
+  hex::encode(mac.finalize().into_bytes())
+}
+
+/// Webhook subscriptions and their deliveries
+pub struct Webhooks {
+  pub subscriptions: Mutex<VecPack<WebhookSubscription>>,
+  pub deliveries: Mutex<VecPack<WebhookDelivery>>,
+  client: reqwest::Client,
+  max_attempts: u32,
+}
+
+impl Webhooks {
+  pub fn new(
+    subscriptions: VecPack<WebhookSubscription>,
+    deliveries: VecPack<WebhookDelivery>,
+  ) -> Self {
+    Self {
+      subscriptions: Mutex::new(subscriptions),
+      deliveries: Mutex::new(deliveries),
+      client: http_client(),
+      max_attempts: env_or("PROCUREMENT_WEBHOOK_MAX_ATTEMPTS", 8),
+    }
+  }
+
+  /// Post event to the subscription URL
+  async fn post(
+    &self,
+    subscription: &WebhookSubscription,
+    delivery: &WebhookDelivery,
+  ) -> Result<(), String> {
+    let payload: serde_json::Value =
+      serde_json::from_str(&delivery.event.payload).map_err(|e| e.to_string())?;
+    let body = serde_json::json!({
+      "id": delivery.event.id,
+      "event_type": delivery.event.event_type,
+      "payload": payload,
+      "created_at": delivery.event.created_at.to_rfc3339(),
+    })
+    .to_string();
+    self
+      .client
+      .post(&subscription.url)
+      .header("Content-Type", "application/json")
+      .header("X-Webhook-Id", &delivery.event.id)
+      .header("X-Webhook-Event", &delivery.event.event_type)
+      .header(
+        "X-Webhook-Signature",
+        format!("sha256={}", sign(&subscription.secret, &body)),
+      )
+      .body(body)
+      .send()
+      .await
+      .and_then(|res| res.error_for_status())
+      .map_err(|e| e.to_string())?;
+    Ok(())
+  }
+
+  /// Try every due delivery once
+  /// Subscriptions are served concurrently, the deliveries of
+  /// a subscription one by one in event order, so a slow partner
+  /// does not hold back the others.
+  /// Delivered ones and the ones of deleted subscriptions are removed
+  pub async fn deliver_due(&self) {
+    let now = Utc::now();
+    // Do not hold the locks while delivering
+    let mut due = self
+      .deliveries
+      .lock()
+      .await
+      .iter()
+      .map(|d| d.unpack())
+      .filter(|d| !d.dead && d.next_attempt_at <= now)
+      .cloned()
+      .collect::<Vec<WebhookDelivery>>();
+    due.sort_by_key(|d| d.event.created_at);
+
+    let mut by_subscription: BTreeMap<u32, Vec<WebhookDelivery>> = BTreeMap::new();
+    for delivery in due {
+      by_subscription
+        .entry(delivery.subscription_id)
+        .or_default()
+        .push(delivery);
+    }
+
+    futures::future::join_all(
+      by_subscription
+        .into_iter()
+        .map(|(subscription_id, deliveries)| {
+          self.deliver_subscription(subscription_id, deliveries)
+        }),
+    )
+    .await;
+  }
+
+  /// Try the deliveries of a subscription in order
+  async fn deliver_subscription(&self, subscription_id: u32, due: Vec<WebhookDelivery>) {
+    let subscription = self
+      .subscriptions
+      .lock()
+      .await
+      .find_id(&subscription_id)
+      .map(|s| s.unpack().clone());
+    for delivery in due {
+      let result = match &subscription {
+        Ok(subscription) => self.post(subscription, &delivery).await,
+        // Subscription is deleted, nothing to deliver
+        Err(_) => Ok(()),
+      };
+      let mut deliveries = self.deliveries.lock().await;
+      let res = match result {
+        Ok(_) => deliveries.remove_pack(&delivery.id).map(|_| ()),
+        Err(e) => {
+          eprintln!("Error while delivering webhook {}: {}", delivery.id, e);
+          deliveries.find_id_mut(&delivery.id).map(|d| {
+            d.as_mut().unpack().attempt_failed(e, self.max_attempts);
+          })
+        }
+      };
+      if let Err(e) = res {
+        eprintln!("Error while updating webhook delivery: {}", e);
+      }
+    }
+  }
+}
+
+/// Integration event sink creating the webhook deliveries
+/// of the subscriptions interested in the event
+pub struct WebhookSink {
+  webhooks: Arc<Webhooks>,
+}
+
+impl WebhookSink {
+  pub fn new(webhooks: Arc<Webhooks>) -> Self {
+    Self { webhooks }
+  }
+}
+
+#[tonic::async_trait]
+impl EventSink for WebhookSink {
+  async fn deliver(&self, event: &IntegrationEvent) -> Result<(), String> {
+    let subscription_ids = self
+      .webhooks
+      .subscriptions
+      .lock()
+      .await
+      .iter()
+      .map(|s| s.unpack())
+      .filter(|s| s.wants(&event.event_type))
+      .map(|s| s.id)
+      .collect::<Vec<u32>>();
+    let mut deliveries = self.webhooks.deliveries.lock().await;
+    for subscription_id in subscription_ids {
+      let delivery = WebhookDelivery::new(subscription_id, event.clone());
+      // Event can be relayed more than once
+      if deliveries.find_id(&delivery.id).is_ok() {
+        continue;
+      }
+      deliveries.insert(delivery).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_sign() {
+    // RFC 4231 test case 2
+    assert_eq!(
+      sign("Jefe", "what do ya want for nothing?"),
+      "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+    assert_ne!(sign("other", "body"), sign("secret", "body"));
+  }
+
+  #[test]
+  fn test_attempt_failed() {
+    let mut delivery = WebhookDelivery::default();
+    delivery.attempt_failed("hiba".into(), 3);
+    assert_eq!(delivery.attempts, 1);
+    assert_eq!(delivery.last_error, "hiba");
+    assert!(delivery.last_attempt_at.is_some());
+    assert!(!delivery.dead);
+    let delay = delivery.next_attempt_at - Utc::now();
+    assert!(delay <= chrono::Duration::seconds(10) && delay > chrono::Duration::seconds(5));
+    delivery.attempt_failed("hiba".into(), 3);
+    let delay = delivery.next_attempt_at - Utc::now();
+    assert!(delay <= chrono::Duration::seconds(20) && delay > chrono::Duration::seconds(15));
+    delivery.attempt_failed("hiba".into(), 3);
+    assert!(delivery.dead);
+    // Delay is capped at one hour
+    for _ in 0..10 {
+      delivery.attempt_failed("hiba".into(), 20);
+    }
+    assert!(delivery.next_attempt_at - Utc::now() <= chrono::Duration::seconds(3600));
+  }
+
+  #[test]
+  fn test_replay() {
+    let mut delivery = WebhookDelivery::default();
+    assert!(delivery.replay().is_err());
+    delivery.attempt_failed("hiba".into(), 1);
+    assert!(delivery.replay().is_ok());
+    assert!(!delivery.dead);
+    assert_eq!(delivery.attempts, 0);
+  }
+}